@@ -0,0 +1,144 @@
+//! Command line tool for administering the network.
+//!
+//! Usage: admin command [options]
+//!
+//! The available commands are:
+//! - `usage [--from YYYY-MM-DD] [--to YYYY-MM-DD] [--client name]`: show the traffic of the
+//!   clients day by day, by default in the last 30 days.
+//! - `keys`: show the status of the key rotations of the servers and the clients that need a new
+//!   configuration.
+//! - `ack-config client`: mark the configuration of a client as up to date, after installing a
+//!   newly generated one on the client.
+
+#[macro_use]
+extern crate log;
+
+use crate::schema::DnsLabel;
+use failure::{bail, Error};
+
+pub mod config;
+pub mod schema;
+pub mod topology;
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    env_logger::init();
+
+    let args: Vec<_> = std::env::args().collect();
+    if args.len() < 2 || args.iter().any(|a| *a == "--help" || *a == "-h") {
+        usage(&args[0]);
+    }
+
+    let config = config::read()?;
+
+    // Connect to the database.
+    debug!("Connecting to the database");
+    let client = schema::connect(&config.database_url).await?;
+    debug!("Connected to the database");
+
+    match args[1].as_str() {
+        "usage" => traffic_usage(&client, &args[2..]).await?,
+        "keys" => key_rotations(&client).await?,
+        "ack-config" => match args.get(2) {
+            Some(name) => ack_config(&client, name).await?,
+            None => usage(&args[0]),
+        },
+        _ => usage(&args[0]),
+    }
+
+    Ok(())
+}
+
+/// Print the usage of the tool and exit.
+fn usage(program: &str) -> ! {
+    eprintln!("Usage: {} command [options]", program);
+    eprintln!();
+    eprintln!("Commands:");
+    eprintln!("    usage [--from YYYY-MM-DD] [--to YYYY-MM-DD] [--client name]");
+    eprintln!("    keys");
+    eprintln!("    ack-config client");
+    std::process::exit(1);
+}
+
+/// Find the value of an option in the form `--name value`.
+fn get_option(args: &[String], name: &str) -> Option<String> {
+    args.iter()
+        .position(|a| a == name)
+        .and_then(|i| args.get(i + 1))
+        .cloned()
+}
+
+/// Print the traffic of the clients day by day.
+async fn traffic_usage(client: &tokio_postgres::Client, args: &[String]) -> Result<(), Error> {
+    let from = get_option(args, "--from");
+    let to = get_option(args, "--to");
+    let name = get_option(args, "--client");
+    for day in from.iter().chain(to.iter()) {
+        schema::validate_day(day)?;
+    }
+    let usage = schema::get_traffic(client, from, to, name).await?;
+    println!(
+        "{:<12} {:<20} {:<20} {:>15} {:>15}",
+        "day", "client", "server", "received", "sent"
+    );
+    for entry in usage {
+        println!(
+            "{:<12} {:<20} {:<20} {:>15} {:>15}",
+            entry.day, entry.client, entry.server, entry.transfer_rx, entry.transfer_tx
+        );
+    }
+    Ok(())
+}
+
+/// Print the status of the key rotations of the servers and the clients with an outdated
+/// configuration.
+async fn key_rotations(client: &tokio_postgres::Client) -> Result<(), Error> {
+    let servers = schema::get_servers(client).await?;
+    let links = schema::get_server_links(client).await?;
+    let topology = topology::Topology::new(&servers, &links);
+    let acks = schema::get_server_key_acks(client).await?;
+    for server in &servers {
+        let pending_public_key = match &server.pending_public_key {
+            Some(key) => key,
+            None => continue,
+        };
+        println!(
+            "Server {} is rotating to {}",
+            server.name, pending_public_key
+        );
+        for neighbor in topology.neighbors(&server.name) {
+            let acked = acks.iter().any(|ack| {
+                ack.server == server.name
+                    && ack.acked_by == neighbor
+                    && ack.public_key == *pending_public_key
+            });
+            let status = if acked { "acknowledged" } else { "waiting" };
+            println!("    {:<20} {}", neighbor, status);
+        }
+    }
+    let outdated = schema::get_outdated_configs(client).await?;
+    if outdated.is_empty() {
+        println!("All the client configurations are up to date");
+    } else {
+        println!("Clients that need a new configuration:");
+        for (name, server) in outdated {
+            println!("    {:<20} key of {} changed", name, server);
+        }
+        println!("Run ack-config after installing their new configuration");
+    }
+    Ok(())
+}
+
+/// Mark the configuration of a client as up to date.
+async fn ack_config(client: &tokio_postgres::Client, name: &str) -> Result<(), Error> {
+    let name = name.parse::<DnsLabel>()?;
+    if schema::get_client(client, name.as_str()).await?.is_none() {
+        bail!("The client {} does not exist", name);
+    }
+    if schema::mark_config_updated(client, name.as_str()).await? == 0 {
+        println!("The configuration of {} was already up to date", name);
+    } else {
+        println!("The configuration of {} is now up to date", name);
+    }
+    Ok(())
+}
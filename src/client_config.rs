@@ -0,0 +1,747 @@
+use failure::{bail, Error};
+use serde::{Serialize, Serializer};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// The name of the wireguard interface of the clients, for the formats that need it.
+const CLIENT_INTERFACE: &str = "wg0";
+
+/// The configuration of a client, independent from the format it's rendered to.
+#[derive(Debug, Clone, Serialize)]
+pub struct ClientConfig {
+    /// The name of the client.
+    pub name: String,
+    /// The base domain of the network.
+    pub domain: String,
+    /// The private key of the client, or a placeholder if not known.
+    pub private_key: String,
+    /// The addresses of the client, with the length of the private network they belong to.
+    #[serde(serialize_with = "serialize_networks")]
+    pub addresses: Vec<(IpAddr, u8)>,
+    /// The MTU of the wireguard interface, if not the default one.
+    pub mtu: Option<u32>,
+    /// The DNS servers to use for the network.
+    pub dns_servers: Vec<String>,
+    /// Whether the DNS servers are recursive resolvers, usable for all the names. Otherwise they
+    /// answer only for the domains of the network, and they are used only for them.
+    pub dns_recursive: bool,
+    /// The search domains of the network.
+    pub dns_search: Vec<String>,
+    /// The servers the client connects to, the preferred one first.
+    pub peers: Vec<ClientPeer>,
+}
+
+/// A server in the configuration of a client.
+#[derive(Debug, Clone, Serialize)]
+pub struct ClientPeer {
+    /// The name of the server.
+    pub server: String,
+    /// The public key of the server.
+    pub public_key: String,
+    /// The preshared key of the connection, if any.
+    pub preshared_key: Option<String>,
+    /// The networks routed through the server.
+    #[serde(serialize_with = "serialize_networks")]
+    pub allowed_ips: Vec<(IpAddr, u8)>,
+    /// The hostname or the address of the server, resolved by the client.
+    pub endpoint_host: String,
+    /// The port of the server.
+    pub endpoint_port: u16,
+    /// The keepalive of the peer, if any.
+    pub keepalive: Option<u32>,
+}
+
+/// The formats a client configuration can be rendered to.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ClientFormat {
+    /// The INI file of wg-quick, the default.
+    WgQuick,
+    /// A NetworkManager keyfile, to put in `/etc/NetworkManager/system-connections`.
+    NetworkManager,
+    /// The `.netdev` and `.network` files of systemd-networkd.
+    Networkd,
+    /// The sections of `/etc/config/network` of OpenWrt.
+    OpenWrt,
+    /// A MikroTik RouterOS script.
+    MikroTik,
+    /// The JSON representation of the configuration.
+    Json,
+}
+
+impl Default for ClientFormat {
+    fn default() -> Self {
+        ClientFormat::WgQuick
+    }
+}
+
+impl FromStr for ClientFormat {
+    type Err = Error;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        Ok(match format.to_lowercase().as_str() {
+            "wg-quick" | "wg" | "ini" => ClientFormat::WgQuick,
+            "networkmanager" | "nm" => ClientFormat::NetworkManager,
+            "networkd" | "systemd" => ClientFormat::Networkd,
+            "openwrt" | "uci" => ClientFormat::OpenWrt,
+            "mikrotik" | "routeros" => ClientFormat::MikroTik,
+            "json" => ClientFormat::Json,
+            _ => bail!(
+                "Unknown format {}, use one of: wg-quick, networkmanager, networkd, openwrt, \
+                 mikrotik, json",
+                format
+            ),
+        })
+    }
+}
+
+impl ClientPeer {
+    /// The endpoint of the server, with the IPv6 addresses in brackets.
+    pub fn endpoint(&self) -> String {
+        match IpAddr::from_str(&self.endpoint_host) {
+            Ok(IpAddr::V6(addr)) => format!("[{}]:{}", addr, self.endpoint_port),
+            _ => format!("{}:{}", self.endpoint_host, self.endpoint_port),
+        }
+    }
+}
+
+impl ClientConfig {
+    /// Render the configuration in the specified format.
+    pub fn render(&self, format: ClientFormat) -> Result<String, Error> {
+        Ok(match format {
+            ClientFormat::WgQuick => self.to_wg_quick(),
+            ClientFormat::NetworkManager => self.to_network_manager(),
+            ClientFormat::Networkd => self.to_networkd(),
+            ClientFormat::OpenWrt => self.to_openwrt(),
+            ClientFormat::MikroTik => self.to_mikrotik(),
+            ClientFormat::Json => serde_json::to_string_pretty(self)?,
+        })
+    }
+
+    /// The DNS servers of an IP family, the ones that are not addresses are skipped.
+    fn dns_servers_of(&self, ipv4: bool) -> Vec<IpAddr> {
+        self.dns_servers
+            .iter()
+            .filter_map(|s| IpAddr::from_str(s).ok())
+            .filter(|s| s.is_ipv4() == ipv4)
+            .collect()
+    }
+
+    /// The domains of the DNS configuration: the search domains when the DNS servers are
+    /// recursive, otherwise routing-only domains (`~domain`), resolved only by the DNS servers of
+    /// the network.
+    fn dns_domains(&self) -> Vec<String> {
+        if self.dns_recursive {
+            self.dns_search.clone()
+        } else {
+            self.dns_search.iter().map(|d| format!("~{}", d)).collect()
+        }
+    }
+
+    /// Render the configuration for wg-quick. wg-quick uses the DNS servers for all the names, so
+    /// they are set only if they are recursive.
+    fn to_wg_quick(&self) -> String {
+        let mut conf = String::new();
+        conf += "[Interface]\n";
+        conf += &format!("PrivateKey = {}\n", self.private_key);
+        conf += &format!("Address = {}\n", join_networks(&self.addresses, ","));
+        if !self.dns_servers.is_empty() && self.dns_recursive {
+            let dns: Vec<_> = self.dns_servers.iter().chain(&self.dns_search).collect();
+            conf += &format!("DNS = {}\n", join(&dns, ", "));
+        }
+        if let Some(mtu) = self.mtu {
+            conf += &format!("MTU = {}\n", mtu);
+        }
+        for peer in &self.peers {
+            conf += "\n";
+            conf += "[Peer]\n";
+            conf += &format!("PublicKey = {}\n", peer.public_key);
+            if let Some(preshared_key) = &peer.preshared_key {
+                conf += &format!("PresharedKey = {}\n", preshared_key);
+            }
+            if !peer.allowed_ips.is_empty() {
+                conf += &format!("AllowedIPs = {}\n", join_networks(&peer.allowed_ips, ", "));
+            }
+            conf += &format!("Endpoint = {}\n", peer.endpoint());
+            if let Some(keepalive) = peer.keepalive {
+                conf += &format!("PersistentKeepalive = {}\n", keepalive);
+            }
+        }
+        conf
+    }
+
+    /// Render the configuration as a NetworkManager keyfile. The routes are added by
+    /// NetworkManager from the allowed IPs of the peers.
+    fn to_network_manager(&self) -> String {
+        let mut conf = String::new();
+        conf += "[connection]\n";
+        conf += &format!("id={}\n", self.domain);
+        conf += "type=wireguard\n";
+        conf += &format!("interface-name={}\n", CLIENT_INTERFACE);
+        conf += "\n[wireguard]\n";
+        conf += &format!("private-key={}\n", self.private_key);
+        if let Some(mtu) = self.mtu {
+            conf += &format!("mtu={}\n", mtu);
+        }
+        for peer in &self.peers {
+            conf += &format!("\n[wireguard-peer.{}]\n", peer.public_key);
+            conf += &format!("endpoint={}\n", peer.endpoint());
+            if let Some(preshared_key) = &peer.preshared_key {
+                conf += &format!("preshared-key={}\n", preshared_key);
+                conf += "preshared-key-flags=0\n";
+            }
+            if let Some(keepalive) = peer.keepalive {
+                conf += &format!("persistent-keepalive={}\n", keepalive);
+            }
+            if !peer.allowed_ips.is_empty() {
+                conf += &format!("allowed-ips={};\n", join_networks(&peer.allowed_ips, ";"));
+            }
+        }
+        for (section, ipv4) in &[("ipv4", true), ("ipv6", false)] {
+            conf += &format!("\n[{}]\n", section);
+            let addresses: Vec<_> = self
+                .addresses
+                .iter()
+                .filter(|(addr, _)| addr.is_ipv4() == *ipv4)
+                .collect();
+            if addresses.is_empty() {
+                conf += if *ipv4 {
+                    "method=disabled\n"
+                } else {
+                    "method=ignore\n"
+                };
+                continue;
+            }
+            conf += "method=manual\n";
+            for (index, (addr, len)) in addresses.iter().enumerate() {
+                conf += &format!("address{}={}/{}\n", index + 1, addr, len);
+            }
+            let dns = self.dns_servers_of(*ipv4);
+            if !dns.is_empty() {
+                conf += &format!("dns={};\n", join(&dns, ";"));
+                conf += &format!("dns-search={};\n", join(&self.dns_domains(), ";"));
+            }
+        }
+        conf
+    }
+
+    /// Render the configuration as the `.netdev` and `.network` files of systemd-networkd, one
+    /// after the other.
+    fn to_networkd(&self) -> String {
+        let mut conf = String::new();
+        conf += &format!("# /etc/systemd/network/90-{}.netdev\n", CLIENT_INTERFACE);
+        conf += "[NetDev]\n";
+        conf += &format!("Name={}\n", CLIENT_INTERFACE);
+        conf += "Kind=wireguard\n";
+        if let Some(mtu) = self.mtu {
+            conf += &format!("MTUBytes={}\n", mtu);
+        }
+        conf += "\n[WireGuard]\n";
+        conf += &format!("PrivateKey={}\n", self.private_key);
+        for peer in &self.peers {
+            conf += "\n[WireGuardPeer]\n";
+            conf += &format!("PublicKey={}\n", peer.public_key);
+            if let Some(preshared_key) = &peer.preshared_key {
+                conf += &format!("PresharedKey={}\n", preshared_key);
+            }
+            if !peer.allowed_ips.is_empty() {
+                conf += &format!("AllowedIPs={}\n", join_networks(&peer.allowed_ips, ", "));
+            }
+            conf += &format!("Endpoint={}\n", peer.endpoint());
+            if let Some(keepalive) = peer.keepalive {
+                conf += &format!("PersistentKeepalive={}\n", keepalive);
+            }
+        }
+        conf += &format!("\n# /etc/systemd/network/90-{}.network\n", CLIENT_INTERFACE);
+        conf += "[Match]\n";
+        conf += &format!("Name={}\n", CLIENT_INTERFACE);
+        conf += "\n[Network]\n";
+        for (addr, len) in &self.addresses {
+            conf += &format!("Address={}/{}\n", addr, len);
+        }
+        for dns in &self.dns_servers {
+            conf += &format!("DNS={}\n", dns);
+        }
+        if !self.dns_servers.is_empty() {
+            conf += &format!("Domains={}\n", join(&self.dns_domains(), " "));
+        }
+        for (addr, len) in self.routes() {
+            conf += "\n[Route]\n";
+            conf += &format!("Destination={}/{}\n", addr, len);
+        }
+        conf += &self.exit_node_note();
+        conf
+    }
+
+    /// Render the configuration as the sections of `/etc/config/network` of OpenWrt. The DNS
+    /// servers of the interface are used for all the names, so they are set only if they are
+    /// recursive. The routes are added explicitly, like for the other formats that don't add them
+    /// from the allowed IPs.
+    fn to_openwrt(&self) -> String {
+        let mut conf = String::new();
+        conf += &format!("config interface '{}'\n", CLIENT_INTERFACE);
+        conf += "\toption proto 'wireguard'\n";
+        conf += &format!("\toption private_key '{}'\n", self.private_key);
+        for (addr, len) in &self.addresses {
+            conf += &format!("\tlist addresses '{}/{}'\n", addr, len);
+        }
+        if let Some(mtu) = self.mtu {
+            conf += &format!("\toption mtu '{}'\n", mtu);
+        }
+        if self.dns_recursive {
+            for dns in &self.dns_servers {
+                conf += &format!("\tlist dns '{}'\n", dns);
+            }
+        }
+        if !self.dns_servers.is_empty() && self.dns_recursive {
+            for search in &self.dns_search {
+                conf += &format!("\tlist dns_search '{}'\n", search);
+            }
+        }
+        for peer in &self.peers {
+            conf += &format!("\nconfig wireguard_{}\n", CLIENT_INTERFACE);
+            conf += &format!("\toption description '{}'\n", peer.server);
+            conf += &format!("\toption public_key '{}'\n", peer.public_key);
+            if let Some(preshared_key) = &peer.preshared_key {
+                conf += &format!("\toption preshared_key '{}'\n", preshared_key);
+            }
+            for (addr, len) in &peer.allowed_ips {
+                conf += &format!("\tlist allowed_ips '{}/{}'\n", addr, len);
+            }
+            conf += "\toption route_allowed_ips '0'\n";
+            conf += &format!("\toption endpoint_host '{}'\n", peer.endpoint_host);
+            conf += &format!("\toption endpoint_port '{}'\n", peer.endpoint_port);
+            if let Some(keepalive) = peer.keepalive {
+                conf += &format!("\toption persistent_keepalive '{}'\n", keepalive);
+            }
+        }
+        for (addr, len) in self.routes() {
+            let section = if addr.is_ipv4() { "route" } else { "route6" };
+            conf += &format!("\nconfig {}\n", section);
+            conf += &format!("\toption interface '{}'\n", CLIENT_INTERFACE);
+            conf += &format!("\toption target '{}/{}'\n", addr, len);
+        }
+        conf += &self.exit_node_note();
+        conf
+    }
+
+    /// Render the configuration as a MikroTik RouterOS script. The queries for the base domain are
+    /// forwarded to the DNS servers of the network.
+    fn to_mikrotik(&self) -> String {
+        let mut conf = String::new();
+        let mtu = match self.mtu {
+            Some(mtu) => format!(" mtu={}", mtu),
+            None => String::new(),
+        };
+        conf += &format!(
+            "/interface wireguard add name={} private-key=\"{}\"{}\n",
+            CLIENT_INTERFACE, self.private_key, mtu
+        );
+        for peer in &self.peers {
+            let mut line = format!(
+                "/interface wireguard peers add interface={} public-key=\"{}\"",
+                CLIENT_INTERFACE, peer.public_key
+            );
+            if let Some(preshared_key) = &peer.preshared_key {
+                line += &format!(" preshared-key=\"{}\"", preshared_key);
+            }
+            line += &format!(
+                " endpoint-address={} endpoint-port={}",
+                peer.endpoint_host, peer.endpoint_port
+            );
+            if !peer.allowed_ips.is_empty() {
+                line += &format!(" allowed-address={}", join_networks(&peer.allowed_ips, ","));
+            }
+            if let Some(keepalive) = peer.keepalive {
+                line += &format!(" persistent-keepalive={}s", keepalive);
+            }
+            line += &format!(" comment=\"{}\"\n", peer.server);
+            conf += &line;
+        }
+        for (addr, len) in &self.addresses {
+            if addr.is_ipv4() {
+                conf += &format!(
+                    "/ip address add address={}/{} interface={}\n",
+                    addr, len, CLIENT_INTERFACE
+                );
+            } else {
+                conf += &format!(
+                    "/ipv6 address add address={}/{} interface={} advertise=no\n",
+                    addr, len, CLIENT_INTERFACE
+                );
+            }
+        }
+        for (addr, len) in self.routes() {
+            let menu = if addr.is_ipv4() { "/ip" } else { "/ipv6" };
+            conf += &format!(
+                "{} route add dst-address={}/{} gateway={}\n",
+                menu, addr, len, CLIENT_INTERFACE
+            );
+        }
+        if let Some(dns) = self.dns_servers.first() {
+            for search in &self.dns_search {
+                conf += &format!(
+                    "/ip dns static add name={} type=FWD forward-to={} match-subdomain=yes\n",
+                    search, dns
+                );
+            }
+        }
+        conf += &self.exit_node_note();
+        conf
+    }
+
+    /// A comment listing the exit nodes whose default routes are not added, for the formats that
+    /// add the routes explicitly. Unlike wg-quick and NetworkManager they can't exclude the traffic
+    /// to the servers from a default route through the tunnel, so it has to be added by hand.
+    fn exit_node_note(&self) -> String {
+        let mut note = String::new();
+        for peer in &self.peers {
+            for (addr, len) in &peer.allowed_ips {
+                if *len == 0 {
+                    note += &format!(
+                        "# {} is an exit node: route {}/0 through {} excluding {}\n",
+                        peer.server, addr, CLIENT_INTERFACE, peer.endpoint_host
+                    );
+                }
+            }
+        }
+        if note.is_empty() {
+            note
+        } else {
+            format!("\n{}", note)
+        }
+    }
+
+    /// The routes to add for the formats that don't add them from the allowed IPs, excluding the
+    /// default routes and the networks of the addresses of the client, already routed by them.
+    fn routes(&self) -> Vec<(IpAddr, u8)> {
+        let mut routes = Vec::new();
+        for peer in &self.peers {
+            for network in &peer.allowed_ips {
+                let (addr, len) = *network;
+                let connected = self
+                    .addresses
+                    .iter()
+                    .any(|(a, l)| *l == len && same_network(*a, addr, len));
+                if len == 0 || connected || routes.contains(network) {
+                    continue;
+                }
+                routes.push(*network);
+            }
+        }
+        routes
+    }
+}
+
+/// Join a list of networks in CIDR notation.
+fn join_networks(networks: &[(IpAddr, u8)], separator: &str) -> String {
+    let networks: Vec<_> = networks
+        .iter()
+        .map(|(addr, len)| format!("{}/{}", addr, len))
+        .collect();
+    networks.join(separator)
+}
+
+/// Serialize a list of networks in CIDR notation.
+fn serialize_networks<S: Serializer>(
+    networks: &[(IpAddr, u8)],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(
+        networks
+            .iter()
+            .map(|(addr, len)| format!("{}/{}", addr, len)),
+    )
+}
+
+/// Whether the two addresses are in the same network of the specified length.
+fn same_network(a: IpAddr, b: IpAddr, len: u8) -> bool {
+    let (a, b, bits) = match (a, b) {
+        (IpAddr::V4(a), IpAddr::V4(b)) => (u32::from(a) as u128, u32::from(b) as u128, 32),
+        (IpAddr::V6(a), IpAddr::V6(b)) => (u128::from(a), u128::from(b), 128),
+        _ => return false,
+    };
+    let shift = bits - u32::from(len.min(bits as u8));
+    shift >= bits || a >> shift == b >> shift
+}
+
+/// Join a list of values.
+fn join<T: ToString>(values: &[T], separator: &str) -> String {
+    let values: Vec<_> = values.iter().map(|v| v.to_string()).collect();
+    values.join(separator)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_config() -> ClientConfig {
+        ClientConfig {
+            name: "client".to_string(),
+            domain: "vpn.example.com".to_string(),
+            private_key: "client-private".to_string(),
+            addresses: vec![
+                (IpAddr::from([10, 0, 1, 2]), 16),
+                (IpAddr::from_str("fd12::1:2").unwrap(), 48),
+            ],
+            mtu: None,
+            dns_servers: vec![],
+            dns_recursive: false,
+            dns_search: vec![],
+            peers: vec![ClientPeer {
+                server: "alpha".to_string(),
+                public_key: "alpha-public".to_string(),
+                preshared_key: None,
+                allowed_ips: vec![
+                    (IpAddr::from([10, 0, 0, 0]), 16),
+                    (IpAddr::from_str("fd12::").unwrap(), 48),
+                ],
+                endpoint_host: "alpha.example.com".to_string(),
+                endpoint_port: 51820,
+                keepalive: None,
+            }],
+        }
+    }
+
+    fn with_dns(recursive: bool) -> ClientConfig {
+        let mut config = test_config();
+        config.dns_servers = vec!["10.0.0.1".to_string(), "fd12::1".to_string()];
+        config.dns_recursive = recursive;
+        config.dns_search = vec!["vpn.example.com".to_string()];
+        config
+    }
+
+    fn exit_node() -> ClientConfig {
+        let mut config = test_config();
+        config.peers[0].allowed_ips = vec![
+            (IpAddr::from([0, 0, 0, 0]), 0),
+            (IpAddr::from_str("::").unwrap(), 0),
+        ];
+        config.peers.push(ClientPeer {
+            server: "beta".to_string(),
+            public_key: "beta-public".to_string(),
+            preshared_key: None,
+            allowed_ips: vec![],
+            endpoint_host: "beta.example.com".to_string(),
+            endpoint_port: 51820,
+            keepalive: None,
+        });
+        config
+    }
+
+    #[test]
+    fn test_wg_quick() {
+        let mut config = test_config();
+        config.mtu = Some(1380);
+        config.peers[0].keepalive = Some(25);
+        assert_eq!(
+            config.render(ClientFormat::WgQuick).unwrap(),
+            "[Interface]\n\
+             PrivateKey = client-private\n\
+             Address = 10.0.1.2/16,fd12::1:2/48\n\
+             MTU = 1380\n\
+             \n\
+             [Peer]\n\
+             PublicKey = alpha-public\n\
+             AllowedIPs = 10.0.0.0/16, fd12::/48\n\
+             Endpoint = alpha.example.com:51820\n\
+             PersistentKeepalive = 25\n"
+        );
+    }
+
+    #[test]
+    fn test_network_manager() {
+        let mut config = test_config();
+        config.addresses.truncate(1);
+        let conf = config.render(ClientFormat::NetworkManager).unwrap();
+        assert!(conf.contains("[wireguard-peer.alpha-public]\nendpoint=alpha.example.com:51820\n"));
+        assert!(conf.contains("allowed-ips=10.0.0.0/16;fd12::/48;\n"));
+        assert!(conf.contains("[ipv4]\nmethod=manual\naddress1=10.0.1.2/16\n"));
+        assert!(conf.contains("[ipv6]\nmethod=ignore\n"));
+    }
+
+    #[test]
+    fn test_networkd() {
+        let conf = test_config().render(ClientFormat::Networkd).unwrap();
+        assert!(conf.contains("[WireGuardPeer]\nPublicKey=alpha-public\n"));
+        assert!(conf.contains("Endpoint=alpha.example.com:51820\n"));
+        assert!(conf.contains("Address=10.0.1.2/16\nAddress=fd12::1:2/48\n"));
+        // the private networks are already routed by the addresses
+        assert!(!conf.contains("[Route]"));
+    }
+
+    #[test]
+    fn test_openwrt() {
+        let conf = test_config().render(ClientFormat::OpenWrt).unwrap();
+        assert!(conf.contains("\tlist addresses '10.0.1.2/16'\n\tlist addresses 'fd12::1:2/48'\n"));
+        assert!(conf.contains("\toption route_allowed_ips '0'\n"));
+        assert!(conf.contains("\toption endpoint_host 'alpha.example.com'\n"));
+        assert!(conf.contains("\toption endpoint_port '51820'\n"));
+        assert!(!conf.contains("config route"));
+    }
+
+    #[test]
+    fn test_mikrotik() {
+        let conf = test_config().render(ClientFormat::MikroTik).unwrap();
+        assert_eq!(
+            conf,
+            "/interface wireguard add name=wg0 private-key=\"client-private\"\n\
+             /interface wireguard peers add interface=wg0 public-key=\"alpha-public\" \
+             endpoint-address=alpha.example.com endpoint-port=51820 \
+             allowed-address=10.0.0.0/16,fd12::/48 comment=\"alpha\"\n\
+             /ip address add address=10.0.1.2/16 interface=wg0\n\
+             /ipv6 address add address=fd12::1:2/48 interface=wg0 advertise=no\n"
+        );
+    }
+
+    #[test]
+    fn test_json() {
+        let conf = test_config().render(ClientFormat::Json).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&conf).unwrap();
+        assert_eq!(json["addresses"][1], "fd12::1:2/48");
+        assert_eq!(json["peers"][0]["allowed_ips"][0], "10.0.0.0/16");
+    }
+
+    #[test]
+    fn test_exit_node() {
+        let config = exit_node();
+        // wg-quick and NetworkManager install the default routes keeping the traffic to the
+        // servers out of the tunnel
+        let conf = config.render(ClientFormat::WgQuick).unwrap();
+        assert!(conf.contains("AllowedIPs = 0.0.0.0/0, ::/0\n"));
+        let conf = config.render(ClientFormat::NetworkManager).unwrap();
+        assert!(conf.contains("allowed-ips=0.0.0.0/0;::/0;\n"));
+        // the other servers are peers without routes
+        assert_eq!(conf.matches("allowed-ips=").count(), 1);
+        let conf = config.render(ClientFormat::WgQuick).unwrap();
+        assert!(conf.contains("PublicKey = beta-public\nEndpoint = beta.example.com:51820\n"));
+        // the other formats route only the more specific networks, and tell which default routes
+        // are missing
+        let note =
+            "# alpha is an exit node: route 0.0.0.0/0 through wg0 excluding alpha.example.com\n\
+                    # alpha is an exit node: route ::/0 through wg0 excluding alpha.example.com\n";
+        let conf = config.render(ClientFormat::Networkd).unwrap();
+        assert!(!conf.contains("Destination=0.0.0.0/0"));
+        assert!(conf.ends_with(note));
+        let conf = config.render(ClientFormat::OpenWrt).unwrap();
+        assert!(conf.contains("\tlist allowed_ips '0.0.0.0/0'\n"));
+        assert!(!conf.contains("target '0.0.0.0/0'"));
+        assert!(conf.ends_with(note));
+        let conf = config.render(ClientFormat::MikroTik).unwrap();
+        assert!(conf.contains("endpoint-port=51820 comment=\"beta\"\n"));
+        assert!(!conf.contains("dst-address=0.0.0.0/0"));
+        assert!(conf.ends_with(note));
+        // without exit nodes there is no note
+        let conf = test_config().render(ClientFormat::Networkd).unwrap();
+        assert!(!conf.contains("exit node"));
+    }
+
+    #[test]
+    fn test_ipv6_endpoint() {
+        let mut config = test_config();
+        config.peers[0].endpoint_host = "2001:db8::1".to_string();
+        assert_eq!(config.peers[0].endpoint(), "[2001:db8::1]:51820");
+        let conf = config.render(ClientFormat::WgQuick).unwrap();
+        assert!(conf.contains("Endpoint = [2001:db8::1]:51820\n"));
+        let conf = config.render(ClientFormat::NetworkManager).unwrap();
+        assert!(conf.contains("endpoint=[2001:db8::1]:51820\n"));
+        let conf = config.render(ClientFormat::Networkd).unwrap();
+        assert!(conf.contains("Endpoint=[2001:db8::1]:51820\n"));
+        // OpenWrt and MikroTik have separate fields for the host and the port
+        let conf = config.render(ClientFormat::OpenWrt).unwrap();
+        assert!(conf.contains("\toption endpoint_host '2001:db8::1'\n"));
+        let conf = config.render(ClientFormat::MikroTik).unwrap();
+        assert!(conf.contains("endpoint-address=2001:db8::1 endpoint-port=51820"));
+        // IPv4 addresses and hostnames are not bracketed
+        config.peers[0].endpoint_host = "192.0.2.1".to_string();
+        assert_eq!(config.peers[0].endpoint(), "192.0.2.1:51820");
+    }
+
+    #[test]
+    fn test_preshared_key() {
+        let conf = test_config().render(ClientFormat::WgQuick).unwrap();
+        assert!(!conf.contains("PresharedKey"));
+        let mut config = test_config();
+        config.peers[0].preshared_key = Some("alpha-psk".to_string());
+        let conf = config.render(ClientFormat::WgQuick).unwrap();
+        assert!(conf.contains("PublicKey = alpha-public\nPresharedKey = alpha-psk\n"));
+        let conf = config.render(ClientFormat::NetworkManager).unwrap();
+        assert!(conf.contains("preshared-key=alpha-psk\npreshared-key-flags=0\n"));
+        let conf = config.render(ClientFormat::Networkd).unwrap();
+        assert!(conf.contains("PresharedKey=alpha-psk\n"));
+        let conf = config.render(ClientFormat::OpenWrt).unwrap();
+        assert!(conf.contains("\toption preshared_key 'alpha-psk'\n"));
+        let conf = config.render(ClientFormat::MikroTik).unwrap();
+        assert!(conf.contains("preshared-key=\"alpha-psk\""));
+        let conf = config.render(ClientFormat::Json).unwrap();
+        assert!(conf.contains("\"preshared_key\": \"alpha-psk\""));
+    }
+
+    #[test]
+    fn test_format_from_str() {
+        assert_eq!(
+            ClientFormat::from_str("wg-quick").unwrap(),
+            ClientFormat::WgQuick
+        );
+        assert_eq!(
+            ClientFormat::from_str("NM").unwrap(),
+            ClientFormat::NetworkManager
+        );
+        assert_eq!(
+            ClientFormat::from_str("routeros").unwrap(),
+            ClientFormat::MikroTik
+        );
+        assert!(ClientFormat::from_str("ifupdown").is_err());
+    }
+
+    #[test]
+    fn test_recursive_dns() {
+        let config = with_dns(true);
+        let conf = config.render(ClientFormat::WgQuick).unwrap();
+        assert!(conf.contains("DNS = 10.0.0.1, fd12::1, vpn.example.com\n"));
+        let conf = config.render(ClientFormat::NetworkManager).unwrap();
+        assert!(conf.contains("dns=10.0.0.1;\ndns-search=vpn.example.com;\n"));
+        assert!(conf.contains("dns=fd12::1;\ndns-search=vpn.example.com;\n"));
+        let conf = config.render(ClientFormat::Networkd).unwrap();
+        assert!(conf.contains("DNS=10.0.0.1\nDNS=fd12::1\nDomains=vpn.example.com\n"));
+        let conf = config.render(ClientFormat::OpenWrt).unwrap();
+        assert!(conf.contains("\tlist dns '10.0.0.1'\n\tlist dns 'fd12::1'\n"));
+        assert!(conf.contains("\tlist dns_search 'vpn.example.com'\n"));
+        let conf = config.render(ClientFormat::MikroTik).unwrap();
+        assert!(conf.contains(
+            "/ip dns static add name=vpn.example.com type=FWD forward-to=10.0.0.1 \
+             match-subdomain=yes\n"
+        ));
+    }
+
+    #[test]
+    fn test_zone_only_dns() {
+        // the servers answer only for the zone of the network, they must not be used for the
+        // other names
+        let config = with_dns(false);
+        let conf = config.render(ClientFormat::WgQuick).unwrap();
+        assert!(!conf.contains("DNS"));
+        let conf = config.render(ClientFormat::NetworkManager).unwrap();
+        assert!(conf.contains("dns=10.0.0.1;\ndns-search=~vpn.example.com;\n"));
+        let conf = config.render(ClientFormat::Networkd).unwrap();
+        assert!(conf.contains("DNS=10.0.0.1\nDNS=fd12::1\nDomains=~vpn.example.com\n"));
+        let conf = config.render(ClientFormat::OpenWrt).unwrap();
+        assert!(!conf.contains("dns"));
+        let conf = config.render(ClientFormat::MikroTik).unwrap();
+        assert!(conf.contains("type=FWD forward-to=10.0.0.1"));
+    }
+
+    #[test]
+    fn test_no_dns() {
+        let config = test_config();
+        for format in &[
+            ClientFormat::WgQuick,
+            ClientFormat::NetworkManager,
+            ClientFormat::Networkd,
+            ClientFormat::OpenWrt,
+            ClientFormat::MikroTik,
+        ] {
+            let conf = config.render(*format).unwrap().to_lowercase();
+            assert!(!conf.contains("dns"), "{:?}: {}", format, conf);
+        }
+    }
+}
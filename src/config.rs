@@ -1,6 +1,21 @@
 use failure::{format_err, Error};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::IpAddr;
 use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::schema::DnsLabel;
+
+/// Default number of seconds between two publications of the status of the peers.
+const DEFAULT_STATUS_INTERVAL: u64 = 30;
+
+/// Default number of seconds between two resolutions of the hostnames of the servers.
+const DEFAULT_RESOLVE_INTERVAL: u64 = 300;
+
+/// Default port of the built-in DNS server.
+const DEFAULT_DNS_LISTEN_PORT: u16 = 53;
 
 /// The private configuration of a server.
 #[derive(Debug, Clone, Eq, Ord, PartialOrd, PartialEq, Deserialize, Serialize)]
@@ -9,6 +24,15 @@ pub struct ServerConfig {
     pub name: String,
     /// The private key of the server.
     pub private_key: String,
+    /// The new private key of the server, for rotating its key. It's used as soon as all the other
+    /// servers accept it.
+    pub pending_private_key: Option<String>,
+    /// The preshared keys of the links with the other servers, by name of the other server. The
+    /// other server must have the same key in its configuration file.
+    #[serde(default)]
+    pub link_preshared_keys: BTreeMap<String, String>,
+    /// Path to the file where the preshared keys of the clients are kept, generated by this server.
+    pub preshared_keys_file: Option<PathBuf>,
     /// An optional keep-alive to use for every peer.
     pub keepalive: Option<u32>,
     /// The name of the network device to create.
@@ -17,18 +41,143 @@ pub struct ServerConfig {
     pub database_url: String,
     /// Domain suffix to use for the DNS, without the leading dot.
     pub base_domain: String,
-    /// Path to the file where to put the hosts entries. Use --hostsdir in dnsmasq.
-    pub dns_hosts_file: PathBuf,
+    /// Path to the file where to put the hosts entries. Use --hostsdir in dnsmasq. This is a
+    /// shorthand for a `hosts` DNS backend reloading dnsmasq.
+    pub dns_hosts_file: Option<PathBuf>,
+    /// The DNS servers the records of the network are exported to.
+    #[serde(default)]
+    pub dns_backends: Vec<DnsBackendConfig>,
     /// The entire private network.
     pub network: String,
     /// Length of the subnet of the entire private network.
     pub netmask_len: u8,
+    /// The entire private network of the other IP family, for dual-stack networks.
+    pub secondary_network: Option<String>,
+    /// Length of the subnet of the entire secondary private network.
+    pub secondary_netmask_len: Option<u8>,
     /// Which address to listen to for the web interface
     pub web_listen_address: String,
     /// Which port to listen to for the web interface
     pub web_listen_port: u16,
     /// Path to where the static web content is stored
     pub web_static_dir: PathBuf,
+    /// The network device connected to the internet, used for the traffic of the clients that use
+    /// this server as exit node.
+    pub uplink_device: Option<String>,
+    /// Every how many seconds the status of the peers is published in the database.
+    pub status_interval: Option<u64>,
+    /// Every how many seconds the hostnames of the other servers are resolved again.
+    pub resolve_interval: Option<u64>,
+    /// The DNS servers written in the client configurations, by default the addresses of the
+    /// servers marked as `dns` in the database.
+    pub client_dns_servers: Option<Vec<String>>,
+    /// Whether the DNS servers of the clients answer only for the names of the network, instead of
+    /// being recursive resolvers: the clients then use them only for its domains.
+    #[serde(default)]
+    pub client_dns_zone_only: bool,
+    /// The search domains written in the client configurations, by default the base domain.
+    pub client_dns_search: Option<Vec<String>>,
+    /// The address the built-in DNS server listens to, if not set the built-in DNS server is
+    /// disabled.
+    pub dns_listen_address: Option<String>,
+    /// Which port the built-in DNS server listens to, both on UDP and TCP.
+    pub dns_listen_port: Option<u16>,
+    /// The webhooks to call on the events of this server.
+    #[serde(default)]
+    pub webhooks: Vec<WebhookConfig>,
+}
+
+/// The configuration of a webhook, called with a JSON POST on the events of the server.
+#[derive(Debug, Clone, Eq, Ord, PartialOrd, PartialEq, Deserialize, Serialize)]
+pub struct WebhookConfig {
+    /// The URL to call, only `http` is supported.
+    pub url: String,
+    /// The secret used for signing the requests with HMAC-SHA256.
+    pub secret: Option<String>,
+    /// The types of the events to send. If not specified only the notable events are sent.
+    pub events: Option<Vec<String>>,
+}
+
+/// The configuration of a DNS backend, a DNS server the records of the network are exported to.
+#[derive(Debug, Clone, Eq, Ord, PartialOrd, PartialEq, Deserialize, Serialize)]
+pub struct DnsBackendConfig {
+    /// The format of the file to write.
+    pub format: DnsFormat,
+    /// Path to the file to write.
+    pub path: PathBuf,
+    /// The shell command to run after writing the file, for reloading the DNS server.
+    pub reload_command: Option<String>,
+    /// The directory where the `zone` backend writes the reverse zones, one file named `db.<zone>`
+    /// for each of them. If not set the reverse zones are not written.
+    pub reverse_dir: Option<PathBuf>,
+}
+
+/// The formats of the files of the DNS backends.
+#[derive(Debug, Clone, Eq, Ord, PartialOrd, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DnsFormat {
+    /// A hosts file, for dnsmasq and the `hosts` plugin of CoreDNS.
+    #[serde(alias = "dnsmasq", alias = "coredns")]
+    Hosts,
+    /// A zone file, for BIND and NSD.
+    #[serde(alias = "bind", alias = "nsd")]
+    Zone,
+    /// The `local-data` entries of unbound.
+    Unbound,
+    /// A JSON file with all the records.
+    Json,
+}
+
+impl ServerConfig {
+    /// The entire private networks, one for each IP family.
+    pub fn networks(&self) -> Vec<(String, u8)> {
+        let mut networks = vec![(self.network.clone(), self.netmask_len)];
+        if let (Some(network), Some(len)) = (&self.secondary_network, self.secondary_netmask_len) {
+            networks.push((network.clone(), len));
+        }
+        networks
+    }
+
+    /// The length of the private network of the same IP family of the address, `None` if the
+    /// network of that family is not configured.
+    pub fn netmask_len_of(&self, address: &IpAddr) -> Option<u8> {
+        self.networks()
+            .into_iter()
+            .find(|(network, _)| match IpAddr::from_str(network) {
+                Ok(network) => network.is_ipv4() == address.is_ipv4(),
+                Err(_) => false,
+            })
+            .map(|(_, len)| len)
+    }
+
+    /// The interval between two publications of the status of the peers.
+    pub fn status_interval(&self) -> Duration {
+        Duration::from_secs(self.status_interval.unwrap_or(DEFAULT_STATUS_INTERVAL))
+    }
+
+    /// The interval between two resolutions of the hostnames of the other servers.
+    pub fn resolve_interval(&self) -> Duration {
+        Duration::from_secs(self.resolve_interval.unwrap_or(DEFAULT_RESOLVE_INTERVAL))
+    }
+
+    /// The DNS backends to update, including the one of `dns_hosts_file`.
+    pub fn dns_backends(&self) -> Vec<DnsBackendConfig> {
+        let mut backends = self.dns_backends.clone();
+        if let Some(path) = &self.dns_hosts_file {
+            backends.push(DnsBackendConfig {
+                format: DnsFormat::Hosts,
+                path: path.clone(),
+                reload_command: Some("pkill -SIGHUP dnsmasq".to_string()),
+                reverse_dir: None,
+            });
+        }
+        backends
+    }
+
+    /// The port of the built-in DNS server.
+    pub fn dns_listen_port(&self) -> u16 {
+        self.dns_listen_port.unwrap_or(DEFAULT_DNS_LISTEN_PORT)
+    }
 }
 
 /// Read the configuration file.
@@ -36,7 +185,63 @@ pub fn read() -> Result<ServerConfig, Error> {
     let file = std::fs::File::open("config.yaml")
         .map_err(|e| format_err!("Cannot read configuration file: {}", e))?;
     let mut config: ServerConfig = serde_yaml::from_reader(file)?;
+    // The name of the server is used in its DNS name.
+    DnsLabel::from_str(&config.name)?;
     // Make sure the directory is absolute.
     config.web_static_dir = config.web_static_dir.canonicalize()?;
     Ok(config)
 }
+
+/// The configuration of the server `name` used by the tests, in the network 10.0.0.0/16, with the
+/// fields of the YAML `extra` added or replaced.
+#[cfg(test)]
+pub fn test_config(name: &str, extra: &str) -> ServerConfig {
+    let mut config: serde_yaml::Mapping = serde_yaml::from_str(&format!(
+        "name: {}\n\
+         private_key: private\n\
+         device_name: wg0\n\
+         database_url: postgresql://localhost\n\
+         base_domain: vpn.example.com\n\
+         network: 10.0.0.0\n\
+         netmask_len: 16\n\
+         web_listen_address: 127.0.0.1\n\
+         web_listen_port: 8080\n\
+         web_static_dir: static\n",
+        name
+    ))
+    .unwrap();
+    if !extra.is_empty() {
+        let extra: serde_yaml::Mapping = serde_yaml::from_str(extra).unwrap();
+        for (key, value) in extra {
+            config.insert(key, value);
+        }
+    }
+    serde_yaml::from_value(serde_yaml::Value::Mapping(config)).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_netmask_len_of() {
+        let v4 = IpAddr::from([10, 0, 1, 1]);
+        let v6 = IpAddr::from_str("fd12::1").unwrap();
+        let config = test_config("alpha", "");
+        assert_eq!(config.netmask_len_of(&v4), Some(16));
+        assert_eq!(config.netmask_len_of(&v6), None);
+        let config = test_config(
+            "alpha",
+            "secondary_network: \"fd12::\"\nsecondary_netmask_len: 48\n",
+        );
+        assert_eq!(config.netmask_len_of(&v4), Some(16));
+        assert_eq!(config.netmask_len_of(&v6), Some(48));
+    }
+
+    #[test]
+    fn test_client_dns_zone_only() {
+        // the DNS servers of the clients are recursive unless told otherwise
+        assert!(!test_config("alpha", "").client_dns_zone_only);
+        assert!(test_config("alpha", "client_dns_zone_only: true\n").client_dns_zone_only);
+    }
+}
@@ -1,66 +1,551 @@
-use failure::Error;
+use failure::{bail, Error};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fs::Permissions;
+use std::net::IpAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::str::FromStr;
+use tempfile::NamedTempFile;
 use tokio_postgres::Client;
 
-use crate::config::ServerConfig;
+use crate::config::{DnsBackendConfig, DnsFormat, ServerConfig};
+use crate::dns_backends;
+use crate::dns_server::DnsZone;
 use crate::schema;
-use crate::schema::{ClientConnection, Server};
+use crate::schema::{ClientConnection, DnsLabel, DnsRecord, Server};
 use tokio::net::process::Command;
 
-/// Update the hosts file of the DNS server.
-pub async fn update_dns(config: &ServerConfig, client: &Client) -> Result<(), Error> {
-    let conf = gen_dns_config(config, client).await?;
-    debug!("DNS configuration:\n{}", conf);
-    tokio::fs::write(config.dns_hosts_file.clone(), conf).await?;
-    // Try to reload dnsmasq without restarting it. IF dnsmasq is not used this can fail, but it's
-    // not a big deal.
-    let child = Command::new("pkill")
-        .arg("-SIGHUP")
-        .arg("dnsmasq")
-        .spawn()?
-        .await?;
-    if child.success() {
-        info!("dnsmasq reloaded successfully");
-    } else {
-        warn!("Failed to reload dnsmasq: {:?}", child.code());
+/// The TTL of all the DNS records.
+pub const DNS_TTL: u32 = 60;
+/// The refresh interval of the SOA record of the zones.
+pub const SOA_REFRESH: u32 = 3600;
+/// The retry interval of the SOA record of the zones.
+pub const SOA_RETRY: u32 = 600;
+/// The expire time of the SOA record of the zones.
+pub const SOA_EXPIRE: u32 = 86400;
+
+/// The DNS records of the network, rendered by the DNS backends.
+#[derive(Debug, Clone, Serialize)]
+pub struct DnsRecords {
+    /// The base domain of the zone, without the trailing dot.
+    pub base_domain: String,
+    /// The name of this server, the name server of the zone.
+    pub nameserver: String,
+    /// The names and the addresses of the servers.
+    pub servers: Vec<DnsHost>,
+    /// The names and the addresses of the clients.
+    pub clients: Vec<DnsHost>,
+    /// The extra records of the zone, from the `dns_records` table.
+    pub custom: Vec<CustomRecord>,
+    /// The reverse zones of the private networks.
+    pub reverse_zones: Vec<String>,
+}
+
+/// A name inside the zone with its addresses.
+#[derive(Debug, Clone, Serialize)]
+pub struct DnsHost {
+    /// The name of the host, without the base domain.
+    pub name: String,
+    /// The addresses of the host.
+    pub addresses: Vec<IpAddr>,
+}
+
+/// An extra record of the zone.
+#[derive(Debug, Clone, Serialize)]
+pub struct CustomRecord {
+    /// The name of the record, without the base domain.
+    pub name: String,
+    /// The content of the record.
+    #[serde(flatten)]
+    pub data: RecordData,
+}
+
+/// The content of an extra record of the zone.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum RecordData {
+    /// An alias of another name of the zone.
+    Cname {
+        /// The name the alias points to, without the base domain.
+        target: String,
+    },
+    /// An address (`A` or `AAAA`) of a service.
+    Address {
+        /// The address of the service.
+        address: IpAddr,
+    },
+    /// A free text record.
+    Txt {
+        /// The content of the record.
+        text: String,
+    },
+    /// The location of a service.
+    Srv {
+        /// The priority of the target.
+        priority: u16,
+        /// The relative weight of the targets with the same priority.
+        weight: u16,
+        /// The port of the service.
+        port: u16,
+        /// The name of the target, without the base domain.
+        target: String,
+    },
+}
+
+impl CustomRecord {
+    /// Parse a record of the `dns_records` table.
+    pub fn parse(record: &DnsRecord) -> Result<CustomRecord, Error> {
+        // the rows inserted before the constraints were added may still be invalid
+        if !is_valid_name(&record.name) {
+            bail!("Invalid name: {}", record.name);
+        }
+        let data = match record.record_type.as_str() {
+            "CNAME" => RecordData::Cname {
+                target: record.value.clone(),
+            },
+            "A" | "AAAA" => RecordData::Address {
+                address: IpAddr::from_str(&record.value)?,
+            },
+            "TXT" => RecordData::Txt {
+                text: record.value.clone(),
+            },
+            "SRV" => {
+                let parts: Vec<&str> = record.value.split_whitespace().collect();
+                if parts.len() != 4 {
+                    bail!("Invalid SRV record: {}", record.value);
+                }
+                RecordData::Srv {
+                    priority: parts[0].parse()?,
+                    weight: parts[1].parse()?,
+                    port: parts[2].parse()?,
+                    target: parts[3].to_string(),
+                }
+            }
+            _ => bail!("Unknown record type {}", record.record_type),
+        };
+        match &data {
+            RecordData::Cname { target } | RecordData::Srv { target, .. } => {
+                if !is_valid_name(target) {
+                    bail!("Invalid target: {}", target);
+                }
+            }
+            RecordData::Txt { text } => {
+                if text.chars().any(char::is_control) {
+                    bail!("Control characters in the text");
+                }
+            }
+            RecordData::Address { .. } => {}
+        }
+        Ok(CustomRecord {
+            name: record.name.clone(),
+            data,
+        })
+    }
+
+    /// The type of the record, as written in the zone files.
+    pub fn record_type(&self) -> &'static str {
+        match &self.data {
+            RecordData::Cname { .. } => "CNAME",
+            RecordData::Address {
+                address: IpAddr::V4(_),
+            } => "A",
+            RecordData::Address {
+                address: IpAddr::V6(_),
+            } => "AAAA",
+            RecordData::Txt { .. } => "TXT",
+            RecordData::Srv { .. } => "SRV",
+        }
     }
-    Ok(())
 }
 
-/// Generate the dns configuration.
-async fn gen_dns_config(config: &ServerConfig, client: &Client) -> Result<String, Error> {
+impl DnsRecords {
+    /// Build the records of the servers and the clients of the network.
+    pub fn new(
+        config: &ServerConfig,
+        servers: &[Server],
+        clients: &[ClientConnection],
+        custom: &[DnsRecord],
+    ) -> DnsRecords {
+        DnsRecords {
+            base_domain: config.base_domain.trim_end_matches('.').to_string(),
+            nameserver: config.name.clone(),
+            servers: servers
+                .iter()
+                .map(|s| DnsHost {
+                    name: s.name.clone(),
+                    addresses: s.addresses(),
+                })
+                .collect(),
+            clients: clients
+                .iter()
+                .map(|c| DnsHost {
+                    name: c.client.name.clone(),
+                    addresses: c.addresses(),
+                })
+                .collect(),
+            custom: custom
+                .iter()
+                .filter_map(|r| match CustomRecord::parse(r) {
+                    Ok(record) => Some(record),
+                    Err(e) => {
+                        warn!("Ignoring the DNS record {}: {:?}", r.name, e);
+                        None
+                    }
+                })
+                .collect(),
+            reverse_zones: reverse_zones(config),
+        }
+    }
+
+    /// The full name of a name inside the zone.
+    pub fn full_name(&self, name: &str) -> String {
+        format!("{}.{}", name, self.base_domain)
+    }
+
+    /// All the hosts of the zone, servers first.
+    pub fn hosts(&self) -> impl Iterator<Item = &DnsHost> {
+        self.servers.iter().chain(self.clients.iter())
+    }
+
+    /// All the addresses of the zone with their name: the ones of the hosts first, then the extra
+    /// ones.
+    pub fn addresses(&self) -> Vec<(&str, IpAddr)> {
+        let mut addresses = Vec::new();
+        for host in self.hosts() {
+            for address in &host.addresses {
+                addresses.push((host.name.as_str(), *address));
+            }
+        }
+        for record in &self.custom {
+            if let RecordData::Address { address } = &record.data {
+                addresses.push((record.name.as_str(), *address));
+            }
+        }
+        addresses
+    }
+
+    /// The addresses of a name of the zone, following the aliases.
+    pub fn resolve(&self, name: &str) -> Vec<IpAddr> {
+        let mut name = name;
+        // bound the number of aliases followed, in case of loops
+        for _ in 0..8 {
+            let target = self.custom.iter().find_map(|r| match &r.data {
+                RecordData::Cname { target } if r.name == name => Some(target),
+                _ => None,
+            });
+            match target {
+                Some(target) => name = target,
+                None => break,
+            }
+        }
+        self.addresses()
+            .into_iter()
+            .filter(|(n, _)| *n == name)
+            .map(|(_, address)| address)
+            .collect()
+    }
+
+    /// The reverse name of each address inside the reverse zones, with the full name it points to.
+    /// An address with more names points to the first one.
+    pub fn pointers(&self) -> Vec<(String, String)> {
+        let mut pointers = Vec::new();
+        let mut seen = HashSet::new();
+        for (name, address) in self.addresses() {
+            let reverse = reverse_name(&address);
+            if self
+                .reverse_zones
+                .iter()
+                .any(|z| reverse.ends_with(&format!(".{}", z)))
+                && seen.insert(reverse.clone())
+            {
+                pointers.push((reverse, self.full_name(name)));
+            }
+        }
+        pointers
+    }
+}
+
+/// Update the files of the DNS backends and the zone of the built-in DNS server.
+pub async fn update_dns(
+    config: &ServerConfig,
+    client: &Client,
+    zone: &DnsZone,
+) -> Result<(), Error> {
     let servers = schema::get_servers(client).await?;
     let clients = schema::get_clients(client, None::<&str>).await?;
-    let mut conf = gen_server_entries(config, &servers);
-    conf += &gen_clients_entries(config, &clients);
-    Ok(conf)
-}
-
-/// Generate the entries of the servers.
-fn gen_server_entries(config: &ServerConfig, servers: &[Server]) -> String {
-    let mut res = String::new();
-    res += "# Servers\n";
-    for server in servers {
-        res += &format!(
-            "{:<20} {}.{}\n",
-            server.address.to_string(),
-            server.name,
-            config.base_domain
+    let custom = schema::get_dns_records(client).await?;
+    let records = DnsRecords::new(config, &servers, &clients, &custom);
+    zone.update(&records);
+    // a failing backend doesn't prevent the update of the others
+    let mut failed = Vec::new();
+    for backend in config.dns_backends() {
+        if let Err(e) = update_backend(&backend, &records).await {
+            error!(
+                "Failed to update the DNS backend {}: {:?}",
+                backend.path.display(),
+                e
+            );
+            failed.push(backend.path.display().to_string());
+        }
+    }
+    if !failed.is_empty() {
+        bail!("Failed to update the DNS backends: {}", failed.join(", "));
+    }
+    Ok(())
+}
+
+/// Write the files of a DNS backend and reload it. The zone backend also writes the reverse zones,
+/// if a directory for them is configured.
+async fn update_backend(backend: &DnsBackendConfig, records: &DnsRecords) -> Result<(), Error> {
+    let renderer = dns_backends::backend(&backend.format);
+    let mut changed =
+        update_file(&backend.path, |previous| renderer.render(records, previous)).await?;
+    if let (DnsFormat::Zone, Some(dir)) = (&backend.format, &backend.reverse_dir) {
+        for zone in &records.reverse_zones {
+            let path = dir.join(format!("db.{}", zone));
+            changed |= update_file(&path, |previous| {
+                Ok(dns_backends::render_reverse_zone(records, zone, previous))
+            })
+            .await?;
+        }
+    }
+    if !changed {
+        debug!("DNS configuration for {:?} is unchanged", backend.format);
+        return Ok(());
+    }
+    let command = match &backend.reload_command {
+        Some(command) => command,
+        None => return Ok(()),
+    };
+    // A failed reload is not fatal: the backend may not be running yet and it will read the file
+    // when it starts.
+    let child = Command::new("sh").arg("-c").arg(command).spawn()?.await?;
+    if child.success() {
+        info!("DNS backend reloaded successfully with: {}", command);
+    } else {
+        warn!(
+            "Failed to reload the DNS backend with {}: {:?}",
+            command,
+            child.code()
         );
     }
-    res
+    Ok(())
+}
+
+/// Render the new content of a file from its current one and write it, returning whether it
+/// changed.
+async fn update_file<F>(path: &Path, render: F) -> Result<bool, Error>
+where
+    F: FnOnce(Option<&str>) -> Result<String, Error>,
+{
+    let previous = tokio::fs::read(path.to_path_buf())
+        .await
+        .ok()
+        .and_then(|p| String::from_utf8(p).ok());
+    let conf = render(previous.as_ref().map(|p| p.as_str()))?;
+    if previous.as_ref() == Some(&conf) {
+        return Ok(false);
+    }
+    debug!("DNS configuration for {}:\n{}", path.display(), conf);
+    write_atomically(path, &conf).await?;
+    Ok(true)
+}
+
+/// Replace the content of a file writing a temporary file in the same directory and renaming it, so
+/// that the DNS server never reads a partially written file.
+async fn write_atomically(path: &Path, content: &str) -> Result<(), Error> {
+    let dir = match path.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+    let tmpfile = NamedTempFile::new_in(dir)?;
+    tokio::fs::write(tmpfile.path().to_path_buf(), content.as_bytes()).await?;
+    // the temporary file is readable only by its owner, the DNS server may run as another user
+    tokio::fs::set_permissions(tmpfile.path().to_path_buf(), Permissions::from_mode(0o644)).await?;
+    tmpfile.persist(path)?;
+    Ok(())
+}
+
+/// Whether a name relative to the base domain is valid: dot-separated DNS labels, which can start
+/// with an underscore for the owners of the SRV records, for at most 253 characters.
+pub fn is_valid_name(name: &str) -> bool {
+    name.len() <= 253
+        && name.split('.').all(|label| {
+            let label = if label.starts_with('_') {
+                &label[1..]
+            } else {
+                label
+            };
+            DnsLabel::from_str(label).is_ok()
+        })
+}
+
+/// The reverse zones (`in-addr.arpa` and `ip6.arpa`) of the private networks. Networks not aligned
+/// to an octet (IPv4) or a nibble (IPv6) use the smallest zone containing them.
+pub fn reverse_zones(config: &ServerConfig) -> Vec<String> {
+    let mut zones = Vec::new();
+    for (network, len) in config.networks() {
+        let network = match IpAddr::from_str(&network) {
+            Ok(network) => network,
+            Err(e) => {
+                warn!("Invalid network {}: {:?}", network, e);
+                continue;
+            }
+        };
+        let labels = reverse_labels(&network);
+        let count = match network {
+            IpAddr::V4(_) => len as usize / 8,
+            IpAddr::V6(_) => len as usize / 4,
+        };
+        let suffix = labels.len() - count.min(labels.len());
+        zones.push(join_reverse(&labels[suffix..], &network));
+    }
+    zones
+}
+
+/// The reverse name of an address, used for its `PTR` record.
+pub fn reverse_name(address: &IpAddr) -> String {
+    join_reverse(&reverse_labels(address), address)
+}
+
+/// The labels of the reverse name of an address, from the least significant one.
+fn reverse_labels(address: &IpAddr) -> Vec<String> {
+    match address {
+        IpAddr::V4(addr) => addr.octets().iter().rev().map(|o| o.to_string()).collect(),
+        IpAddr::V6(addr) => addr
+            .octets()
+            .iter()
+            .rev()
+            .flat_map(|o| vec![o & 0xf, o >> 4])
+            .map(|n| format!("{:x}", n))
+            .collect(),
+    }
 }
 
-/// Generate the entries of the clients.
-fn gen_clients_entries(config: &ServerConfig, clients: &[ClientConnection]) -> String {
-    let mut res = String::new();
-    res += "\n# Clients\n";
-    for client in clients {
-        res += &format!(
-            "{:<20} {}.{}\n",
-            client.address.to_string(),
-            client.client.name,
-            config.base_domain
+/// Join the reverse labels adding the suffix of the family of the address.
+fn join_reverse(labels: &[String], address: &IpAddr) -> String {
+    let suffix = match address {
+        IpAddr::V4(_) => "in-addr.arpa",
+        IpAddr::V6(_) => "ip6.arpa",
+    };
+    labels
+        .iter()
+        .map(|l| l.as_str())
+        .chain(std::iter::once(suffix))
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+
+    fn dns_record(name: &str, record_type: &str, value: &str) -> DnsRecord {
+        DnsRecord {
+            name: name.to_string(),
+            record_type: record_type.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_is_valid_name() {
+        assert!(is_valid_name("git"));
+        assert!(is_valid_name("_sip._udp"));
+        assert!(is_valid_name("a.b-c.d1"));
+        assert!(!is_valid_name(""));
+        assert!(!is_valid_name("a..b"));
+        assert!(!is_valid_name("a."));
+        assert!(!is_valid_name("bad name"));
+        assert!(!is_valid_name("it's"));
+        assert!(!is_valid_name("__double"));
+        assert!(!is_valid_name(&"a".repeat(64)));
+        let long = vec!["a".repeat(63); 4].join(".");
+        assert_eq!(long.len(), 255);
+        assert!(!is_valid_name(&long));
+    }
+
+    #[test]
+    fn test_parse_custom_record() {
+        let record =
+            CustomRecord::parse(&dns_record("_sip._udp", "SRV", "0 5 5060 alice")).unwrap();
+        assert_eq!(record.record_type(), "SRV");
+        match record.data {
+            RecordData::Srv {
+                priority,
+                weight,
+                port,
+                target,
+            } => assert_eq!(
+                (priority, weight, port, target.as_str()),
+                (0, 5, 5060, "alice")
+            ),
+            _ => panic!("Not an SRV record"),
+        }
+        let record = CustomRecord::parse(&dns_record("svc", "AAAA", "fd12::50")).unwrap();
+        assert_eq!(record.record_type(), "AAAA");
+        assert!(CustomRecord::parse(&dns_record("txt", "TXT", "it's \"ok\"")).is_ok());
+        // invalid records are rejected
+        assert!(CustomRecord::parse(&dns_record("txt", "TXT", "a\nb")).is_err());
+        assert!(CustomRecord::parse(&dns_record("bad name", "A", "10.0.0.1")).is_err());
+        assert!(CustomRecord::parse(&dns_record("www", "CNAME", "x' IN A 1.2.3.4")).is_err());
+        assert!(CustomRecord::parse(&dns_record("srv", "SRV", "0 5 alice")).is_err());
+        assert!(CustomRecord::parse(&dns_record("svc", "A", "not an address")).is_err());
+        assert!(CustomRecord::parse(&dns_record("svc", "MX", "10 mail")).is_err());
+    }
+
+    #[test]
+    fn test_reverse_name() {
+        assert_eq!(
+            reverse_name(&IpAddr::from([10, 0, 1, 2])),
+            "2.1.0.10.in-addr.arpa"
+        );
+        assert_eq!(
+            reverse_name(&IpAddr::from_str("fd12::1").unwrap()),
+            "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.2.1.d.f.ip6.arpa"
+        );
+    }
+
+    #[test]
+    fn test_reverse_zones() {
+        let config = test_config("alpha", "");
+        assert_eq!(reverse_zones(&config), vec!["0.10.in-addr.arpa"]);
+        // the networks not aligned use the smallest zone containing them
+        let config = test_config(
+            "alpha",
+            "netmask_len: 12\n\
+             secondary_network: \"fd12::\"\n\
+             secondary_netmask_len: 50\n",
+        );
+        assert_eq!(
+            reverse_zones(&config),
+            vec!["10.in-addr.arpa", "0.0.0.0.0.0.0.0.2.1.d.f.ip6.arpa"]
+        );
+    }
+
+    #[test]
+    fn test_pointers() {
+        // the trailing dot of the domain is not part of the names
+        let config = test_config("alpha", "base_domain: vpn.example.com.\n");
+        let mut records = DnsRecords::new(&config, &[], &[], &[]);
+        assert_eq!(records.base_domain, "vpn.example.com");
+        records.servers.push(DnsHost {
+            name: "alpha".to_string(),
+            addresses: vec![IpAddr::from([10, 0, 1, 1]), IpAddr::from([192, 0, 2, 1])],
+        });
+        records.clients.push(DnsHost {
+            name: "alias".to_string(),
+            addresses: vec![IpAddr::from([10, 0, 1, 1])],
+        });
+        // only the addresses inside the reverse zones, pointing to the first name
+        assert_eq!(
+            records.pointers(),
+            vec![(
+                "1.1.0.10.in-addr.arpa".to_string(),
+                "alpha.vpn.example.com".to_string()
+            )]
         );
     }
-    res
 }
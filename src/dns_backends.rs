@@ -0,0 +1,380 @@
+use failure::Error;
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::DnsFormat;
+use crate::dns::{
+    CustomRecord, DnsHost, DnsRecords, RecordData, DNS_TTL, SOA_EXPIRE, SOA_REFRESH, SOA_RETRY,
+};
+
+/// A DNS server the records of the network are exported to.
+pub trait DnsBackend: Send + Sync {
+    /// Render the records in the format of the backend. `previous` is the current content of the
+    /// file, if any.
+    fn render(&self, records: &DnsRecords, previous: Option<&str>) -> Result<String, Error>;
+}
+
+/// The backend that renders the records in the given format.
+pub fn backend(format: &DnsFormat) -> Box<dyn DnsBackend> {
+    match format {
+        DnsFormat::Hosts => Box::new(HostsBackend),
+        DnsFormat::Zone => Box::new(ZoneBackend),
+        DnsFormat::Unbound => Box::new(UnboundBackend),
+        DnsFormat::Json => Box::new(JsonBackend),
+    }
+}
+
+/// A hosts file, used by dnsmasq (`--addn-hosts`) and by the `hosts` plugin of CoreDNS. Only
+/// addresses can be expressed: the aliases are resolved and the TXT and SRV records are skipped.
+pub struct HostsBackend;
+
+/// A zone file in the RFC 1035 format, used by BIND and NSD.
+pub struct ZoneBackend;
+
+/// The `local-zone` and `local-data` entries of unbound, to include in the `server:` clause.
+pub struct UnboundBackend;
+
+/// A JSON file with all the records, for the external tools.
+pub struct JsonBackend;
+
+impl DnsBackend for HostsBackend {
+    fn render(&self, records: &DnsRecords, _previous: Option<&str>) -> Result<String, Error> {
+        let mut conf = String::new();
+        conf += "# Servers\n";
+        conf += &gen_hosts_entries(records, &records.servers);
+        conf += "\n# Clients\n";
+        conf += &gen_hosts_entries(records, &records.clients);
+        conf += "\n# Records\n";
+        for record in &records.custom {
+            let addresses = match &record.data {
+                RecordData::Address { address } => vec![*address],
+                RecordData::Cname { .. } => records.resolve(&record.name),
+                _ => continue,
+            };
+            for address in addresses {
+                conf += &format!(
+                    "{:<20} {}\n",
+                    address.to_string(),
+                    records.full_name(&record.name)
+                );
+            }
+        }
+        Ok(conf)
+    }
+}
+
+impl DnsBackend for ZoneBackend {
+    fn render(&self, records: &DnsRecords, previous: Option<&str>) -> Result<String, Error> {
+        Ok(with_serial(previous, |serial| gen_zone(records, serial)))
+    }
+}
+
+/// Render the zone file of a reverse zone, for the zone backend. `previous` is the current content
+/// of the file, if any.
+pub fn render_reverse_zone(records: &DnsRecords, zone: &str, previous: Option<&str>) -> String {
+    with_serial(previous, |serial| gen_reverse_zone(records, zone, serial))
+}
+
+/// Generate a zone file with a new serial, unless its content is the same of the previous one.
+fn with_serial<F: Fn(u32) -> String>(previous: Option<&str>, gen: F) -> String {
+    // The serial is bumped only if something changed, otherwise the secondary servers would
+    // transfer the zone at every reconciliation.
+    let previous_serial = previous.and_then(parse_zone_serial);
+    if let (Some(previous), Some(serial)) = (previous, previous_serial) {
+        let conf = gen(serial);
+        if conf == previous {
+            return conf;
+        }
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as u32;
+    let serial = match previous_serial {
+        Some(previous) => now.max(previous + 1),
+        None => now,
+    };
+    gen(serial)
+}
+
+impl DnsBackend for UnboundBackend {
+    fn render(&self, records: &DnsRecords, _previous: Option<&str>) -> Result<String, Error> {
+        let mut conf = String::new();
+        conf += &format!("local-zone: \"{}.\" static\n", records.base_domain);
+        for zone in &records.reverse_zones {
+            conf += &format!("local-zone: \"{}.\" static\n", zone);
+        }
+        for host in records.hosts() {
+            for address in &host.addresses {
+                conf += &format!(
+                    "local-data: \"{}. {} IN {} {}\"\n",
+                    records.full_name(&host.name),
+                    DNS_TTL,
+                    record_type(address),
+                    address
+                );
+            }
+        }
+        for record in &records.custom {
+            conf += &format!(
+                "local-data: '{}. {} IN {} {}'\n",
+                records.full_name(&record.name),
+                DNS_TTL,
+                record.record_type(),
+                record_value(records, record)
+            );
+        }
+        for (reverse, name) in records.pointers() {
+            conf += &format!(
+                "local-data: \"{}. {} IN PTR {}.\"\n",
+                reverse, DNS_TTL, name
+            );
+        }
+        Ok(conf)
+    }
+}
+
+impl DnsBackend for JsonBackend {
+    fn render(&self, records: &DnsRecords, _previous: Option<&str>) -> Result<String, Error> {
+        Ok(serde_json::to_string_pretty(records)?)
+    }
+}
+
+/// Generate the entries of a hosts file, one for each address.
+fn gen_hosts_entries(records: &DnsRecords, hosts: &[DnsHost]) -> String {
+    let mut res = String::new();
+    for host in hosts {
+        for address in &host.addresses {
+            res += &format!(
+                "{:<20} {}\n",
+                address.to_string(),
+                records.full_name(&host.name)
+            );
+        }
+    }
+    res
+}
+
+/// Generate the zone file of the base domain with the specified serial.
+fn gen_zone(records: &DnsRecords, serial: u32) -> String {
+    let mut conf = gen_zone_header(records, &records.base_domain, serial);
+    conf += "\n; Servers\n";
+    conf += &gen_zone_entries(&records.servers);
+    conf += "\n; Clients\n";
+    conf += &gen_zone_entries(&records.clients);
+    conf += "\n; Records\n";
+    for record in &records.custom {
+        conf += &format!(
+            "{:<20} IN {:<4} {}\n",
+            record.name,
+            record.record_type(),
+            record_value(records, record)
+        );
+    }
+    conf
+}
+
+/// Generate the zone file of a reverse zone with the specified serial.
+fn gen_reverse_zone(records: &DnsRecords, zone: &str, serial: u32) -> String {
+    let mut conf = gen_zone_header(records, zone, serial);
+    conf += "\n; Pointers\n";
+    let suffix = format!(".{}", zone);
+    for (reverse, name) in records.pointers() {
+        if reverse.ends_with(&suffix) {
+            let relative = &reverse[..reverse.len() - suffix.len()];
+            conf += &format!("{:<20} IN PTR  {}.\n", relative, name);
+        }
+    }
+    conf
+}
+
+/// Generate the `$ORIGIN`, the `SOA` and the `NS` records of a zone file.
+fn gen_zone_header(records: &DnsRecords, zone: &str, serial: u32) -> String {
+    let nameserver = records.full_name(&records.nameserver);
+    let mut conf = String::new();
+    conf += &format!("$ORIGIN {}.\n", zone);
+    conf += &format!("$TTL {}\n", DNS_TTL);
+    conf += &format!(
+        "@ IN SOA {}. {}. (\n",
+        nameserver,
+        records.full_name("hostmaster")
+    );
+    conf += &format!("    {} ; serial\n", serial);
+    conf += &format!("    {} ; refresh\n", SOA_REFRESH);
+    conf += &format!("    {} ; retry\n", SOA_RETRY);
+    conf += &format!("    {} ; expire\n", SOA_EXPIRE);
+    conf += &format!("    {} ; minimum\n", DNS_TTL);
+    conf += ")\n";
+    conf += &format!("@ IN NS {}.\n", nameserver);
+    conf
+}
+
+/// Generate the address records of a zone file, relative to the origin.
+fn gen_zone_entries(hosts: &[DnsHost]) -> String {
+    let mut res = String::new();
+    for host in hosts {
+        for address in &host.addresses {
+            res += &format!(
+                "{:<20} IN {:<4} {}\n",
+                host.name,
+                record_type(address),
+                address
+            );
+        }
+    }
+    res
+}
+
+/// The value of a record as written in the zone files, with the full names of the targets.
+fn record_value(records: &DnsRecords, record: &CustomRecord) -> String {
+    match &record.data {
+        RecordData::Cname { target } => format!("{}.", records.full_name(target)),
+        RecordData::Address { address } => address.to_string(),
+        RecordData::Txt { text } => quote_text(text),
+        RecordData::Srv {
+            priority,
+            weight,
+            port,
+            target,
+        } => format!(
+            "{} {} {} {}.",
+            priority,
+            weight,
+            port,
+            records.full_name(target)
+        ),
+    }
+}
+
+/// Quote the text of a TXT record, split in character-strings of at most 255 bytes. The quotes, the
+/// backslashes, the apostrophes (which would end the quoting of unbound) and the non-printable bytes
+/// are escaped as `\DDD`.
+fn quote_text(text: &str) -> String {
+    if text.is_empty() {
+        return "\"\"".to_string();
+    }
+    let strings: Vec<_> = text
+        .as_bytes()
+        .chunks(255)
+        .map(|chunk| {
+            let mut quoted = String::from("\"");
+            for &byte in chunk {
+                match byte {
+                    b'"' | b'\\' | b'\'' => quoted += &format!("\\{:03}", byte),
+                    0x20..=0x7e => quoted.push(byte as char),
+                    _ => quoted += &format!("\\{:03}", byte),
+                }
+            }
+            quoted.push('"');
+            quoted
+        })
+        .collect();
+    strings.join(" ")
+}
+
+/// Extract the serial from a zone file generated by `gen_zone`.
+fn parse_zone_serial(zone: &str) -> Option<u32> {
+    zone.lines()
+        .find(|line| line.trim_end().ends_with("; serial"))
+        .and_then(|line| line.split_whitespace().next())
+        .and_then(|serial| serial.parse().ok())
+}
+
+/// The type of the record of an address.
+fn record_type(address: &IpAddr) -> &'static str {
+    match address {
+        IpAddr::V4(_) => "A",
+        IpAddr::V6(_) => "AAAA",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_records() -> DnsRecords {
+        DnsRecords {
+            base_domain: "vpn.example.com".to_string(),
+            nameserver: "alpha".to_string(),
+            servers: vec![DnsHost {
+                name: "alpha".to_string(),
+                addresses: vec![IpAddr::from([10, 0, 1, 1])],
+            }],
+            clients: vec![DnsHost {
+                name: "client".to_string(),
+                addresses: vec![IpAddr::from([10, 0, 1, 2])],
+            }],
+            custom: vec![],
+            reverse_zones: vec!["0.10.in-addr.arpa".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_quote_text() {
+        assert_eq!(quote_text(""), "\"\"");
+        assert_eq!(quote_text("v=spf1 -all"), "\"v=spf1 -all\"");
+        assert_eq!(
+            quote_text("it's \"a\" \\ é"),
+            "\"it\\039s \\034a\\034 \\092 \\195\\169\""
+        );
+        // the long texts are split in more strings of at most 255 bytes
+        let quoted = quote_text(&"a".repeat(300));
+        assert_eq!(
+            quoted,
+            format!("\"{}\" \"{}\"", "a".repeat(255), "a".repeat(45))
+        );
+    }
+
+    #[test]
+    fn test_unbound_txt() {
+        let mut records = test_records();
+        records.custom.push(CustomRecord {
+            name: "txt".to_string(),
+            data: RecordData::Txt {
+                text: "x' IN A 1.2.3.4".to_string(),
+            },
+        });
+        let conf = UnboundBackend.render(&records, None).unwrap();
+        // the apostrophe cannot end the quoting of the record
+        assert!(
+            conf.contains("local-data: 'txt.vpn.example.com. 60 IN TXT \"x\\039 IN A 1.2.3.4\"'\n")
+        );
+    }
+
+    #[test]
+    fn test_parse_zone_serial() {
+        let zone = gen_zone(&test_records(), 1_571_000_000);
+        assert_eq!(parse_zone_serial(&zone), Some(1_571_000_000));
+        assert_eq!(parse_zone_serial("$ORIGIN vpn.example.com.\n"), None);
+        assert_eq!(parse_zone_serial("    nope ; serial\n"), None);
+    }
+
+    #[test]
+    fn test_zone_serial() {
+        let records = test_records();
+        let first = ZoneBackend.render(&records, None).unwrap();
+        let serial = parse_zone_serial(&first).unwrap();
+        // unchanged zone, same serial
+        assert_eq!(ZoneBackend.render(&records, Some(&first)).unwrap(), first);
+        // a change bumps the serial, even in the same second
+        let mut changed = records.clone();
+        changed.clients[0].addresses[0] = IpAddr::from([10, 0, 1, 3]);
+        let second = ZoneBackend.render(&changed, Some(&first)).unwrap();
+        assert!(parse_zone_serial(&second).unwrap() > serial);
+        assert!(second.contains("client               IN A    10.0.1.3\n"));
+    }
+
+    #[test]
+    fn test_reverse_zone() {
+        let records = test_records();
+        let zone = render_reverse_zone(&records, "0.10.in-addr.arpa", None);
+        assert!(zone.starts_with("$ORIGIN 0.10.in-addr.arpa.\n"));
+        assert!(zone.contains("@ IN NS alpha.vpn.example.com.\n"));
+        assert!(zone.contains("1.1                  IN PTR  alpha.vpn.example.com.\n"));
+        assert!(zone.contains("2.1                  IN PTR  client.vpn.example.com.\n"));
+        assert_eq!(
+            render_reverse_zone(&records, "0.10.in-addr.arpa", Some(&zone)),
+            zone
+        );
+    }
+}
@@ -0,0 +1,841 @@
+use failure::{bail, Error};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::timer::Timeout;
+
+use crate::config::ServerConfig;
+use crate::dns::{DnsRecords, RecordData, DNS_TTL, SOA_EXPIRE, SOA_REFRESH, SOA_RETRY};
+
+/// The maximum size of a response sent over UDP, larger responses are truncated.
+const MAX_UDP_SIZE: usize = 512;
+/// The time a TCP client has for sending a query and reading its response, an idle or too slow
+/// connection is closed.
+const TCP_TIMEOUT: Duration = Duration::from_secs(10);
+/// The maximum number of TCP connections served at the same time, the others are closed right away.
+const MAX_TCP_CONNECTIONS: usize = 64;
+
+const TYPE_A: u16 = 1;
+const TYPE_NS: u16 = 2;
+const TYPE_CNAME: u16 = 5;
+const TYPE_SOA: u16 = 6;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+
+const RCODE_NOERROR: u8 = 0;
+const RCODE_FORMERR: u8 = 1;
+const RCODE_NXDOMAIN: u8 = 3;
+const RCODE_NOTIMP: u8 = 4;
+const RCODE_REFUSED: u8 = 5;
+
+/// The zone of the network served by the built-in DNS server, kept in memory and updated on every
+/// reconciliation.
+#[derive(Debug, Default)]
+pub struct DnsZone {
+    /// The content of the zone.
+    records: RwLock<ZoneRecords>,
+}
+
+/// The records of the zone.
+#[derive(Debug, Default)]
+struct ZoneRecords {
+    /// The base domain of the zone, lowercase and without the trailing dot.
+    base_domain: String,
+    /// The name server of the zone, this server.
+    nameserver: String,
+    /// The serial of the zone, the UNIX timestamp of the last update.
+    serial: u32,
+    /// The addresses of each name inside the zone, without the base domain.
+    hosts: HashMap<String, Vec<IpAddr>>,
+    /// The target of each alias inside the zone, both without the base domain.
+    aliases: HashMap<String, String>,
+    /// The encoded TXT and SRV records of each name inside the zone, without the base domain.
+    extra: HashMap<String, Vec<(u16, Vec<u8>)>>,
+    /// The reverse zones of the private networks.
+    reverse_zones: Vec<String>,
+    /// The full name of each reverse name inside the reverse zones.
+    pointers: HashMap<String, String>,
+}
+
+/// A resource record of a response.
+struct Record {
+    /// The full name of the record.
+    name: String,
+    /// The type of the record.
+    rtype: u16,
+    /// The encoded data of the record.
+    data: Vec<u8>,
+}
+
+impl DnsZone {
+    /// Replace the content of the zone and of the reverse zones with the records of the network.
+    pub fn update(&self, dns_records: &DnsRecords) {
+        let mut hosts: HashMap<String, Vec<IpAddr>> = HashMap::new();
+        for host in dns_records.hosts() {
+            hosts
+                .entry(host.name.to_lowercase())
+                .or_default()
+                .extend(host.addresses.iter().cloned());
+        }
+        let base_domain = dns_records.base_domain.to_lowercase();
+        let mut aliases = HashMap::new();
+        let mut extra: HashMap<String, Vec<(u16, Vec<u8>)>> = HashMap::new();
+        for record in &dns_records.custom {
+            let name = record.name.to_lowercase();
+            let mut data = Vec::new();
+            let rtype = match &record.data {
+                RecordData::Address { address } => {
+                    hosts.entry(name).or_default().push(*address);
+                    continue;
+                }
+                RecordData::Cname { target } => {
+                    aliases.insert(name, target.to_lowercase());
+                    continue;
+                }
+                RecordData::Txt { text } => {
+                    // the text is split in character-strings of at most 255 bytes
+                    for chunk in text.as_bytes().chunks(255) {
+                        data.push(chunk.len() as u8);
+                        data.extend_from_slice(chunk);
+                    }
+                    if text.is_empty() {
+                        data.push(0);
+                    }
+                    TYPE_TXT
+                }
+                RecordData::Srv {
+                    priority,
+                    weight,
+                    port,
+                    target,
+                } => {
+                    data.extend_from_slice(&priority.to_be_bytes());
+                    data.extend_from_slice(&weight.to_be_bytes());
+                    data.extend_from_slice(&port.to_be_bytes());
+                    write_name(
+                        &mut data,
+                        &format!("{}.{}", target.to_lowercase(), base_domain),
+                    );
+                    TYPE_SRV
+                }
+            };
+            extra.entry(name).or_default().push((rtype, data));
+        }
+        let pointers = dns_records
+            .pointers()
+            .into_iter()
+            .map(|(reverse, name)| (reverse, name.to_lowercase()))
+            .collect();
+        let serial = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as u32;
+        let mut records = self.records.write().unwrap();
+        *records = ZoneRecords {
+            base_domain,
+            nameserver: dns_records.nameserver.to_lowercase(),
+            // make sure the serial increases even with more updates in the same second
+            serial: serial.max(records.serial + 1),
+            hosts,
+            aliases,
+            extra,
+            reverse_zones: dns_records.reverse_zones.clone(),
+            pointers,
+        };
+        debug!(
+            "DNS zone updated with {} names and {} reverse names",
+            records.hosts.len(),
+            records.pointers.len()
+        );
+    }
+
+    /// Build the response to a DNS query, `None` if the query is too malformed for answering.
+    fn answer(&self, query: &[u8]) -> Option<Vec<u8>> {
+        if query.len() < 12 {
+            return None;
+        }
+        let id = [query[0], query[1]];
+        let flags = u16::from_be_bytes([query[2], query[3]]);
+        // only queries are answered
+        if flags & 0x8000 != 0 {
+            return None;
+        }
+        let opcode = ((flags >> 11) & 0xf) as u8;
+        let recursion_desired = flags & 0x0100 != 0;
+        let qdcount = u16::from_be_bytes([query[4], query[5]]);
+        if opcode != 0 {
+            return Some(error_response(id, recursion_desired, RCODE_NOTIMP, None));
+        }
+        if qdcount != 1 {
+            return Some(error_response(id, recursion_desired, RCODE_FORMERR, None));
+        }
+        let (name, offset) = match read_name(query, 12) {
+            Some(res) => res,
+            None => return Some(error_response(id, recursion_desired, RCODE_FORMERR, None)),
+        };
+        if query.len() < offset + 4 {
+            return Some(error_response(id, recursion_desired, RCODE_FORMERR, None));
+        }
+        let qtype = u16::from_be_bytes([query[offset], query[offset + 1]]);
+        let qclass = u16::from_be_bytes([query[offset + 2], query[offset + 3]]);
+        let question = Some((name.as_str(), qtype, qclass));
+
+        let records = self.records.read().unwrap();
+        let name = name.to_lowercase();
+        let zone = match records.zone_of(&name) {
+            Some(zone) => zone,
+            // not authoritative for this name
+            None => {
+                return Some(error_response(
+                    id,
+                    recursion_desired,
+                    RCODE_REFUSED,
+                    question,
+                ))
+            }
+        };
+        if qclass != CLASS_IN {
+            return Some(error_response(
+                id,
+                recursion_desired,
+                RCODE_NOTIMP,
+                question,
+            ));
+        }
+        let mut answers = Vec::new();
+        let mut additional = Vec::new();
+        let mut rcode = RCODE_NOERROR;
+        if name == zone {
+            // the apex of one of the zones
+            if qtype == TYPE_SOA || qtype == TYPE_ANY {
+                answers.push(records.soa_record(&zone));
+            }
+            if qtype == TYPE_NS || qtype == TYPE_ANY {
+                answers.push(records.ns_record(&zone));
+                additional = records.address_records(&records.nameserver, TYPE_ANY);
+            }
+        } else if zone == records.base_domain {
+            let label = &name[..name.len() - zone.len() - 1];
+            if let Some(target) = records.aliases.get(label) {
+                let mut data = Vec::new();
+                write_name(&mut data, &records.full_name(target));
+                answers.push(Record {
+                    name: name.clone(),
+                    rtype: TYPE_CNAME,
+                    data,
+                });
+                // the records of the target are added, so that the resolvers can avoid querying it
+                if qtype != TYPE_CNAME {
+                    answers.extend(records.name_records(target, qtype));
+                }
+            } else if records.hosts.contains_key(label) || records.extra.contains_key(label) {
+                answers = records.name_records(label, qtype);
+            } else {
+                rcode = RCODE_NXDOMAIN;
+            }
+        } else {
+            match records.pointers.get(&name) {
+                Some(target) if qtype == TYPE_PTR || qtype == TYPE_ANY => {
+                    let mut data = Vec::new();
+                    write_name(&mut data, target);
+                    answers.push(Record {
+                        name: name.clone(),
+                        rtype: TYPE_PTR,
+                        data,
+                    });
+                }
+                Some(_) => {}
+                None => rcode = RCODE_NXDOMAIN,
+            }
+        }
+        let authority = if answers.is_empty() {
+            vec![records.soa_record(&zone)]
+        } else {
+            vec![]
+        };
+        Some(build_response(
+            id,
+            recursion_desired,
+            rcode,
+            question,
+            &answers,
+            &authority,
+            &additional,
+        ))
+    }
+}
+
+impl ZoneRecords {
+    /// The zone the name belongs to, the most specific one if more than one match.
+    fn zone_of(&self, name: &str) -> Option<String> {
+        std::iter::once(&self.base_domain)
+            .chain(&self.reverse_zones)
+            .filter(|zone| name == zone.as_str() || name.ends_with(&format!(".{}", zone)))
+            .max_by_key(|zone| zone.len())
+            .cloned()
+    }
+
+    /// The full name of a label inside the zone.
+    fn full_name(&self, label: &str) -> String {
+        format!("{}.{}", label, self.base_domain)
+    }
+
+    /// The SOA record of a zone.
+    fn soa_record(&self, zone: &str) -> Record {
+        let mut data = Vec::new();
+        write_name(&mut data, &self.full_name(&self.nameserver));
+        write_name(&mut data, &self.full_name("hostmaster"));
+        data.extend_from_slice(&self.serial.to_be_bytes());
+        data.extend_from_slice(&SOA_REFRESH.to_be_bytes());
+        data.extend_from_slice(&SOA_RETRY.to_be_bytes());
+        data.extend_from_slice(&SOA_EXPIRE.to_be_bytes());
+        data.extend_from_slice(&DNS_TTL.to_be_bytes()); // minimum
+        Record {
+            name: zone.to_string(),
+            rtype: TYPE_SOA,
+            data,
+        }
+    }
+
+    /// The NS record of a zone.
+    fn ns_record(&self, zone: &str) -> Record {
+        let mut data = Vec::new();
+        write_name(&mut data, &self.full_name(&self.nameserver));
+        Record {
+            name: zone.to_string(),
+            rtype: TYPE_NS,
+            data,
+        }
+    }
+
+    /// All the records of a name of the zone, filtered by the type of the query.
+    fn name_records(&self, label: &str, qtype: u16) -> Vec<Record> {
+        let mut records = self.address_records(label, qtype);
+        if let Some(extra) = self.extra.get(label) {
+            for (rtype, data) in extra {
+                if qtype == *rtype || qtype == TYPE_ANY {
+                    records.push(Record {
+                        name: self.full_name(label),
+                        rtype: *rtype,
+                        data: data.clone(),
+                    });
+                }
+            }
+        }
+        records
+    }
+
+    /// The A and AAAA records of a name of the zone, filtered by the type of the query.
+    fn address_records(&self, label: &str, qtype: u16) -> Vec<Record> {
+        let addresses = match self.hosts.get(label) {
+            Some(addresses) => addresses,
+            None => return vec![],
+        };
+        let mut records = Vec::new();
+        for address in addresses {
+            let (rtype, data) = match address {
+                IpAddr::V4(addr) => (TYPE_A, addr.octets().to_vec()),
+                IpAddr::V6(addr) => (TYPE_AAAA, addr.octets().to_vec()),
+            };
+            if qtype == rtype || qtype == TYPE_ANY {
+                records.push(Record {
+                    name: self.full_name(label),
+                    rtype,
+                    data,
+                });
+            }
+        }
+        records
+    }
+}
+
+/// Read a domain name from a message, returning it with the offset right after it. Compression
+/// pointers are not supported, they are never used in the questions.
+fn read_name(message: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    loop {
+        let len = *message.get(offset)? as usize;
+        offset += 1;
+        if len == 0 {
+            break;
+        }
+        if len & 0xc0 != 0 {
+            return None;
+        }
+        let label = message.get(offset..offset + len)?;
+        labels.push(String::from_utf8_lossy(label).to_string());
+        offset += len;
+    }
+    Some((labels.join("."), offset))
+}
+
+/// Write a domain name in a message, without compression.
+fn write_name(message: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|l| !l.is_empty()) {
+        message.push(label.len() as u8);
+        message.extend_from_slice(label.as_bytes());
+    }
+    message.push(0);
+}
+
+/// Build a response message without records.
+fn error_response(
+    id: [u8; 2],
+    recursion_desired: bool,
+    rcode: u8,
+    question: Option<(&str, u16, u16)>,
+) -> Vec<u8> {
+    build_response(id, recursion_desired, rcode, question, &[], &[], &[])
+}
+
+/// Build a response message. The response is authoritative unless it's refused.
+fn build_response(
+    id: [u8; 2],
+    recursion_desired: bool,
+    rcode: u8,
+    question: Option<(&str, u16, u16)>,
+    answers: &[Record],
+    authority: &[Record],
+    additional: &[Record],
+) -> Vec<u8> {
+    let mut flags: u16 = 0x8000 | rcode as u16;
+    if rcode != RCODE_REFUSED && rcode != RCODE_FORMERR && rcode != RCODE_NOTIMP {
+        flags |= 0x0400;
+    }
+    if recursion_desired {
+        flags |= 0x0100;
+    }
+    let mut message = Vec::new();
+    message.extend_from_slice(&id);
+    message.extend_from_slice(&flags.to_be_bytes());
+    message.extend_from_slice(&(question.is_some() as u16).to_be_bytes());
+    message.extend_from_slice(&(answers.len() as u16).to_be_bytes());
+    message.extend_from_slice(&(authority.len() as u16).to_be_bytes());
+    message.extend_from_slice(&(additional.len() as u16).to_be_bytes());
+    if let Some((name, qtype, qclass)) = question {
+        write_name(&mut message, name);
+        message.extend_from_slice(&qtype.to_be_bytes());
+        message.extend_from_slice(&qclass.to_be_bytes());
+    }
+    for record in answers.iter().chain(authority).chain(additional) {
+        write_name(&mut message, &record.name);
+        message.extend_from_slice(&record.rtype.to_be_bytes());
+        message.extend_from_slice(&CLASS_IN.to_be_bytes());
+        message.extend_from_slice(&DNS_TTL.to_be_bytes());
+        message.extend_from_slice(&(record.data.len() as u16).to_be_bytes());
+        message.extend_from_slice(&record.data);
+    }
+    message
+}
+
+/// Truncate a response that doesn't fit in a UDP message, keeping only the header and the question
+/// and setting the TC flag, so that the client retries over TCP.
+fn truncate(response: Vec<u8>, question_end: usize) -> Vec<u8> {
+    if response.len() <= MAX_UDP_SIZE {
+        return response;
+    }
+    let mut truncated = response[..question_end].to_vec();
+    truncated[2] |= 0x02;
+    for count in &mut truncated[6..12] {
+        *count = 0;
+    }
+    truncated
+}
+
+/// Spawn the built-in DNS server, listening on UDP and TCP on the address specified in the
+/// configuration file.
+pub async fn spawn_dns_server(config: &ServerConfig, zone: Arc<DnsZone>) -> Result<(), Error> {
+    let address = match &config.dns_listen_address {
+        Some(address) => address,
+        None => bail!("dns_listen_address is not set"),
+    };
+    let addr = SocketAddr::new(IpAddr::from_str(address)?, config.dns_listen_port());
+    let socket = UdpSocket::bind(&addr).await?;
+    spawn_udp_server(socket, zone.clone());
+    let listener = TcpListener::bind(&addr).await?;
+    spawn_tcp_server(listener, zone);
+    info!("DNS server listening on {} (UDP and TCP)", addr);
+    Ok(())
+}
+
+/// Answer the queries received by the UDP socket.
+fn spawn_udp_server(mut socket: UdpSocket, zone: Arc<DnsZone>) {
+    tokio::spawn(async move {
+        let mut buf = [0u8; 4096];
+        loop {
+            let (len, peer) = match socket.recv_from(&mut buf).await {
+                Ok(res) => res,
+                Err(e) => {
+                    warn!("Failed to receive a DNS query: {:?}", e);
+                    continue;
+                }
+            };
+            let response = match zone.answer(&buf[..len]) {
+                Some(response) => response,
+                None => continue,
+            };
+            let question_end = question_end(&response);
+            let response = truncate(response, question_end);
+            if let Err(e) = socket.send_to(&response, &peer).await {
+                warn!("Failed to send the DNS response to {}: {:?}", peer, e);
+            }
+        }
+    });
+}
+
+/// Accept the TCP connections of the listener and answer their queries, serving at most
+/// `MAX_TCP_CONNECTIONS` at the same time.
+fn spawn_tcp_server(mut listener: TcpListener, zone: Arc<DnsZone>) {
+    let connections = Arc::new(AtomicUsize::new(0));
+    tokio::spawn(async move {
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(res) => res,
+                Err(e) => {
+                    warn!("Failed to accept a DNS connection: {:?}", e);
+                    continue;
+                }
+            };
+            if connections.fetch_add(1, Ordering::SeqCst) >= MAX_TCP_CONNECTIONS {
+                connections.fetch_sub(1, Ordering::SeqCst);
+                debug!("Too many DNS connections, closing the one with {}", peer);
+                continue;
+            }
+            let zone = zone.clone();
+            let connections = connections.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handle_tcp_connection(stream, zone.as_ref()).await {
+                    debug!("DNS connection with {} closed: {:?}", peer, e);
+                }
+                connections.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+    });
+}
+
+/// Answer all the queries of a TCP connection, closing it when a query is not completed in time.
+async fn handle_tcp_connection(mut stream: TcpStream, zone: &DnsZone) -> Result<(), Error> {
+    loop {
+        match Timeout::new(answer_tcp_query(&mut stream, zone), TCP_TIMEOUT).await {
+            Ok(res) => res?,
+            Err(_) => bail!("Timed out"),
+        }
+    }
+}
+
+/// Read a query from a TCP connection and write its response, both prefixed by their length.
+async fn answer_tcp_query(stream: &mut TcpStream, zone: &DnsZone) -> Result<(), Error> {
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len).await?;
+    let mut query = vec![0u8; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut query).await?;
+    let response = match zone.answer(&query) {
+        Some(response) => response,
+        None => bail!("Malformed DNS query"),
+    };
+    stream
+        .write_all(&(response.len() as u16).to_be_bytes())
+        .await?;
+    stream.write_all(&response).await?;
+    Ok(())
+}
+
+/// The offset of the end of the question section of a message built by `build_response`.
+fn question_end(message: &[u8]) -> usize {
+    if message.len() < 12 || u16::from_be_bytes([message[4], message[5]]) == 0 {
+        return 12.min(message.len());
+    }
+    match read_name(message, 12) {
+        Some((_, offset)) => (offset + 4).min(message.len()),
+        None => 12,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::DnsHost;
+
+    fn test_zone() -> DnsZone {
+        let records = DnsRecords {
+            base_domain: "vpn.example.com".to_string(),
+            nameserver: "alpha".to_string(),
+            servers: vec![DnsHost {
+                name: "alpha".to_string(),
+                addresses: vec![
+                    IpAddr::from([10, 0, 1, 1]),
+                    IpAddr::from_str("fd12::1").unwrap(),
+                ],
+            }],
+            clients: vec![
+                DnsHost {
+                    name: "Client".to_string(),
+                    addresses: vec![IpAddr::from([10, 0, 1, 2])],
+                },
+                // enough addresses for not fitting in a UDP response
+                DnsHost {
+                    name: "big".to_string(),
+                    addresses: (1..=40)
+                        .map(|i| IpAddr::from_str(&format!("fd12::{:x}", i)).unwrap())
+                        .collect(),
+                },
+            ],
+            custom: vec![],
+            reverse_zones: vec!["10.in-addr.arpa".to_string()],
+        };
+        let zone = DnsZone::default();
+        zone.update(&records);
+        zone
+    }
+
+    fn query(name: &str, qtype: u16) -> Vec<u8> {
+        let mut message = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        write_name(&mut message, name);
+        message.extend_from_slice(&qtype.to_be_bytes());
+        message.extend_from_slice(&CLASS_IN.to_be_bytes());
+        message
+    }
+
+    fn rcode(response: &[u8]) -> u8 {
+        response[3] & 0x0f
+    }
+
+    /// The number of records in the answer, authority and additional sections.
+    fn counts(response: &[u8]) -> (u16, u16, u16) {
+        (
+            u16::from_be_bytes([response[6], response[7]]),
+            u16::from_be_bytes([response[8], response[9]]),
+            u16::from_be_bytes([response[10], response[11]]),
+        )
+    }
+
+    /// The name, the type and the data of all the records of a response.
+    fn records(response: &[u8]) -> Vec<(String, u16, Vec<u8>)> {
+        let (answers, authority, additional) = counts(response);
+        let mut offset = question_end(response);
+        let mut records = Vec::new();
+        for _ in 0..answers + authority + additional {
+            let (name, end) = read_name(response, offset).unwrap();
+            let rtype = u16::from_be_bytes([response[end], response[end + 1]]);
+            let len = u16::from_be_bytes([response[end + 8], response[end + 9]]) as usize;
+            let data = response[end + 10..end + 10 + len].to_vec();
+            records.push((name, rtype, data));
+            offset = end + 10 + len;
+        }
+        assert_eq!(offset, response.len());
+        records
+    }
+
+    #[test]
+    fn test_a() {
+        let response = test_zone()
+            .answer(&query("client.VPN.example.com", TYPE_A))
+            .unwrap();
+        assert_eq!(&response[..2], &[0x12, 0x34]);
+        assert_eq!(rcode(&response), RCODE_NOERROR);
+        // authoritative answer
+        assert_ne!(response[2] & 0x04, 0);
+        assert_eq!(
+            records(&response),
+            vec![(
+                "client.vpn.example.com".to_string(),
+                TYPE_A,
+                vec![10, 0, 1, 2]
+            )]
+        );
+    }
+
+    #[test]
+    fn test_aaaa() {
+        let response = test_zone()
+            .answer(&query("alpha.vpn.example.com", TYPE_AAAA))
+            .unwrap();
+        assert_eq!(rcode(&response), RCODE_NOERROR);
+        let records = records(&response);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].1, TYPE_AAAA);
+        let address: std::net::Ipv6Addr = "fd12::1".parse().unwrap();
+        assert_eq!(records[0].2, address.octets().to_vec());
+    }
+
+    #[test]
+    fn test_soa() {
+        let response = test_zone()
+            .answer(&query("vpn.example.com", TYPE_SOA))
+            .unwrap();
+        assert_eq!(rcode(&response), RCODE_NOERROR);
+        let records = records(&response);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].0, "vpn.example.com");
+        assert_eq!(records[0].1, TYPE_SOA);
+        let (mname, offset) = read_name(&records[0].2, 0).unwrap();
+        assert_eq!(mname, "alpha.vpn.example.com");
+        let (rname, offset) = read_name(&records[0].2, offset).unwrap();
+        assert_eq!(rname, "hostmaster.vpn.example.com");
+        // serial, refresh, retry, expire and minimum
+        assert_eq!(records[0].2.len(), offset + 20);
+    }
+
+    #[test]
+    fn test_ns() {
+        let response = test_zone()
+            .answer(&query("vpn.example.com", TYPE_NS))
+            .unwrap();
+        assert_eq!(rcode(&response), RCODE_NOERROR);
+        // the addresses of the name server are additional records
+        assert_eq!(counts(&response), (1, 0, 2));
+        let records = records(&response);
+        assert_eq!(
+            read_name(&records[0].2, 0).unwrap().0,
+            "alpha.vpn.example.com"
+        );
+        assert_eq!(records[1].0, "alpha.vpn.example.com");
+    }
+
+    #[test]
+    fn test_nxdomain() {
+        let response = test_zone()
+            .answer(&query("nobody.vpn.example.com", TYPE_A))
+            .unwrap();
+        assert_eq!(rcode(&response), RCODE_NXDOMAIN);
+        // the SOA of the zone in the authority section, for the negative caching
+        assert_eq!(counts(&response), (0, 1, 0));
+        assert_eq!(records(&response)[0].1, TYPE_SOA);
+        let response = test_zone()
+            .answer(&query("9.9.9.10.in-addr.arpa", TYPE_PTR))
+            .unwrap();
+        assert_eq!(rcode(&response), RCODE_NXDOMAIN);
+    }
+
+    #[test]
+    fn test_nodata() {
+        let response = test_zone()
+            .answer(&query("client.vpn.example.com", TYPE_AAAA))
+            .unwrap();
+        assert_eq!(rcode(&response), RCODE_NOERROR);
+        assert_eq!(counts(&response), (0, 1, 0));
+        assert_eq!(records(&response)[0].1, TYPE_SOA);
+    }
+
+    #[test]
+    fn test_ptr() {
+        let response = test_zone()
+            .answer(&query("2.1.0.10.in-addr.arpa", TYPE_PTR))
+            .unwrap();
+        assert_eq!(rcode(&response), RCODE_NOERROR);
+        let records = records(&response);
+        assert_eq!(records[0].1, TYPE_PTR);
+        assert_eq!(
+            read_name(&records[0].2, 0).unwrap().0,
+            "client.vpn.example.com"
+        );
+    }
+
+    #[test]
+    fn test_refused() {
+        let response = test_zone().answer(&query("example.org", TYPE_A)).unwrap();
+        assert_eq!(rcode(&response), RCODE_REFUSED);
+        // not authoritative
+        assert_eq!(response[2] & 0x04, 0);
+        assert_eq!(counts(&response), (0, 0, 0));
+    }
+
+    #[test]
+    fn test_formerr() {
+        let zone = test_zone();
+        // two questions
+        let mut message = query("alpha.vpn.example.com", TYPE_A);
+        message[5] = 2;
+        assert_eq!(rcode(&zone.answer(&message).unwrap()), RCODE_FORMERR);
+        // the question is cut
+        let message = query("alpha.vpn.example.com", TYPE_A);
+        let response = zone.answer(&message[..message.len() - 2]).unwrap();
+        assert_eq!(rcode(&response), RCODE_FORMERR);
+        // too short for a header, or a response
+        assert!(zone.answer(&message[..8]).is_none());
+        let mut message = query("alpha.vpn.example.com", TYPE_A);
+        message[2] |= 0x80;
+        assert!(zone.answer(&message).is_none());
+    }
+
+    #[test]
+    fn test_truncation() {
+        let response = test_zone()
+            .answer(&query("big.vpn.example.com", TYPE_AAAA))
+            .unwrap();
+        assert!(response.len() > MAX_UDP_SIZE);
+        assert_eq!(counts(&response).0, 40);
+        let truncated = truncate(response.clone(), question_end(&response));
+        // TC flag set, only the question is kept
+        assert_ne!(truncated[2] & 0x02, 0);
+        assert_eq!(counts(&truncated), (0, 0, 0));
+        assert_eq!(truncated.len(), question_end(&response));
+        // the small responses are not touched
+        let response = test_zone()
+            .answer(&query("alpha.vpn.example.com", TYPE_A))
+            .unwrap();
+        assert_eq!(
+            truncate(response.clone(), question_end(&response)),
+            response
+        );
+    }
+
+    #[tokio::test]
+    async fn test_udp() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        let socket = UdpSocket::bind(&addr).await.unwrap();
+        let server = socket.local_addr().unwrap();
+        spawn_udp_server(socket, Arc::new(test_zone()));
+        let mut client = UdpSocket::bind(&addr).await.unwrap();
+        client
+            .send_to(&query("client.vpn.example.com", TYPE_A), &server)
+            .await
+            .unwrap();
+        let mut buf = [0u8; 4096];
+        let (len, _) = client.recv_from(&mut buf).await.unwrap();
+        assert_eq!(records(&buf[..len])[0].2, vec![10, 0, 1, 2]);
+        // the large responses are truncated
+        client
+            .send_to(&query("big.vpn.example.com", TYPE_AAAA), &server)
+            .await
+            .unwrap();
+        let (len, _) = client.recv_from(&mut buf).await.unwrap();
+        assert_ne!(buf[2] & 0x02, 0);
+        assert!(len <= MAX_UDP_SIZE);
+    }
+
+    #[tokio::test]
+    async fn test_tcp() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        let listener = TcpListener::bind(&addr).await.unwrap();
+        let server = listener.local_addr().unwrap();
+        spawn_tcp_server(listener, Arc::new(test_zone()));
+        let mut stream = TcpStream::connect(&server).await.unwrap();
+        // more queries on the same connection, the large responses are not truncated
+        for (name, qtype, count) in &[
+            ("client.vpn.example.com", TYPE_A, 1),
+            ("big.vpn.example.com", TYPE_AAAA, 40),
+        ] {
+            let message = query(name, *qtype);
+            stream
+                .write_all(&(message.len() as u16).to_be_bytes())
+                .await
+                .unwrap();
+            stream.write_all(&message).await.unwrap();
+            let mut len = [0u8; 2];
+            stream.read_exact(&mut len).await.unwrap();
+            let mut response = vec![0u8; u16::from_be_bytes(len) as usize];
+            stream.read_exact(&mut response).await.unwrap();
+            assert_eq!(records(&response).len(), *count);
+        }
+    }
+}
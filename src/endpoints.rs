@@ -0,0 +1,180 @@
+use failure::{bail, format_err, Error};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::Mutex;
+use tokio::net::process::Command;
+use tokio_executor::blocking;
+use tokio_postgres::Client;
+
+use crate::config::ServerConfig;
+use crate::schema;
+use crate::schema::Server;
+use crate::topology::Topology;
+
+/// The addresses of the servers reachable only by hostname, resolved by this server.
+///
+/// The hostnames are resolved on every reconciliation and periodically re-resolved: when the answer
+/// changes the endpoint of the peer is updated with `wg set`, without rebuilding the configuration.
+#[derive(Debug, Default)]
+pub struct EndpointResolver {
+    /// The last address each hostname resolved to.
+    resolved: Mutex<HashMap<String, IpAddr>>,
+}
+
+impl EndpointResolver {
+    /// Resolve the hostnames of the servers linked with this one, the only ones that are peers of
+    /// this server. Returns the servers whose address changed.
+    pub async fn resolve_neighbors(
+        &self,
+        config: &ServerConfig,
+        servers: &[Server],
+        links: &[(String, String)],
+    ) -> Vec<Server> {
+        let topology = Topology::new(servers, links);
+        let neighbors = topology.neighbors(&config.name);
+        let servers: Vec<_> = servers
+            .iter()
+            .filter(|s| neighbors.contains(&s.name.as_str()))
+            .cloned()
+            .collect();
+        self.resolve(&servers).await
+    }
+
+    /// Resolve the hostnames of the servers, keeping the previous address of the ones that fail to
+    /// resolve. Returns the servers whose address changed.
+    async fn resolve(&self, servers: &[Server]) -> Vec<Server> {
+        let mut changed = Vec::new();
+        for server in servers {
+            let hostname = match &server.public_hostname {
+                Some(hostname) => hostname,
+                None => continue,
+            };
+            let address = match lookup(hostname).await {
+                Ok(address) => address,
+                Err(e) => {
+                    warn!("Failed to resolve {} of {}: {:?}", hostname, server.name, e);
+                    continue;
+                }
+            };
+            let previous = self
+                .resolved
+                .lock()
+                .unwrap()
+                .insert(hostname.clone(), address);
+            if previous != Some(address) {
+                info!("{} of {} resolved to {}", hostname, server.name, address);
+                changed.push(server.clone());
+            }
+        }
+        changed
+    }
+
+    /// The endpoint for reaching a server, if its address is known.
+    pub fn endpoint(&self, server: &Server) -> Option<String> {
+        let address = match &server.public_hostname {
+            Some(hostname) => *self.resolved.lock().unwrap().get(hostname)?,
+            None => server.public_address?,
+        };
+        // the IPv6 addresses are enclosed in brackets
+        Some(SocketAddr::new(address, server.public_port).to_string())
+    }
+
+    /// Re-resolve the hostnames of the servers linked with this one, updating the endpoints of the
+    /// peers whose address changed.
+    pub async fn refresh(&self, config: &ServerConfig, client: &Client) -> Result<(), Error> {
+        let servers = schema::get_servers(client).await?;
+        let links = schema::get_server_links(client).await?;
+        for server in self.resolve_neighbors(config, &servers, &links).await {
+            let endpoint = self.endpoint(&server).unwrap();
+            let child = Command::new("wg")
+                .args(&[
+                    "set",
+                    &config.device_name,
+                    "peer",
+                    &server.public_key,
+                    "endpoint",
+                    &endpoint,
+                ])
+                .spawn()?
+                .await?;
+            if child.success() {
+                info!("Endpoint of {} updated to {}", server.name, endpoint);
+            } else {
+                bail!(
+                    "Failed to update the endpoint of {}: exit code {:?}",
+                    server.name,
+                    child.code()
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Resolve a hostname with the resolver of the system, on the blocking pool.
+async fn lookup(hostname: &str) -> Result<IpAddr, Error> {
+    let host = hostname.to_string();
+    let mut addresses = blocking::run(move || (host.as_str(), 0).to_socket_addrs()).await?;
+    match addresses.next() {
+        Some(address) => Ok(address.ip()),
+        None => Err(format_err!("No address found for {}", hostname)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server(name: &str, address: Option<IpAddr>, hostname: Option<&str>) -> Server {
+        Server {
+            name: name.to_string(),
+            subnet_addr: IpAddr::from([10, 0, 1, 0]),
+            subnet_len: 24,
+            address: IpAddr::from([10, 0, 1, 1]),
+            public_address: address,
+            public_port: 51820,
+            public_key: String::new(),
+            hub: false,
+            public_hostname: hostname.map(String::from),
+            keepalive: None,
+            mtu: None,
+            secondary_subnet_addr: None,
+            secondary_subnet_len: None,
+            secondary_address: None,
+            pending_public_key: None,
+            dns: false,
+        }
+    }
+
+    #[test]
+    fn test_endpoint_of_static_address() {
+        let resolver = EndpointResolver::default();
+        let v4 = server("a", Some(IpAddr::from([192, 0, 2, 1])), None);
+        assert_eq!(resolver.endpoint(&v4).as_deref(), Some("192.0.2.1:51820"));
+        let v6 = server("b", Some("2001:db8::1".parse().unwrap()), None);
+        assert_eq!(
+            resolver.endpoint(&v6).as_deref(),
+            Some("[2001:db8::1]:51820")
+        );
+        assert_eq!(resolver.endpoint(&server("c", None, None)), None);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_hostname() {
+        let resolver = EndpointResolver::default();
+        let servers = vec![
+            server("a", Some(IpAddr::from([192, 0, 2, 1])), Some("localhost")),
+            server("b", None, Some("invalid.invalid")),
+        ];
+        // the hostname takes precedence, it's unknown until it's resolved
+        assert_eq!(resolver.endpoint(&servers[0]), None);
+        let changed = resolver.resolve(&servers).await;
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].name, "a");
+        let endpoint = resolver.endpoint(&servers[0]).unwrap();
+        assert!(endpoint == "127.0.0.1:51820" || endpoint == "[::1]:51820");
+        assert_eq!(resolver.endpoint(&servers[1]), None);
+        // an unchanged address is not reported again
+        assert!(resolver.resolve(&servers).await.is_empty());
+    }
+}
@@ -0,0 +1,170 @@
+use futures::channel::mpsc;
+use serde::Serialize;
+use std::sync::Mutex;
+
+/// The kind of a peer of the wireguard device.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PeerKind {
+    /// The peer is another server of the network.
+    Server,
+    /// The peer is a client connected to this server.
+    Client,
+}
+
+/// An event happened on this server.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// A notification from the database has been received.
+    DatabaseNotification {
+        /// The channel of the notification.
+        channel: String,
+        /// The payload of the notification.
+        payload: String,
+    },
+    /// The configuration of the server has been updated successfully.
+    ReconciliationCompleted,
+    /// The update of the configuration of the server failed.
+    ReconciliationFailed {
+        /// The reason of the failure.
+        error: String,
+    },
+    /// A client has been allowed to connect to this server.
+    ClientAdded {
+        /// The name of the client.
+        name: String,
+    },
+    /// A client is not allowed to connect to this server anymore.
+    ClientRemoved {
+        /// The name of the client.
+        name: String,
+    },
+    /// A peer completed an handshake after being offline.
+    PeerOnline {
+        /// The name of the peer, if known.
+        name: Option<String>,
+        /// The public key of the peer.
+        public_key: String,
+        /// Whether the peer is a server or a client.
+        kind: PeerKind,
+        /// Whether the peer has never been online before, since this server started.
+        first_connection: bool,
+    },
+    /// A peer has not completed an handshake recently.
+    PeerOffline {
+        /// The name of the peer, if known.
+        name: Option<String>,
+        /// The public key of the peer.
+        public_key: String,
+        /// Whether the peer is a server or a client.
+        kind: PeerKind,
+    },
+}
+
+impl Event {
+    /// The name of the type of the event.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::DatabaseNotification { .. } => "database_notification",
+            Event::ReconciliationCompleted => "reconciliation_completed",
+            Event::ReconciliationFailed { .. } => "reconciliation_failed",
+            Event::ClientAdded { .. } => "client_added",
+            Event::ClientRemoved { .. } => "client_removed",
+            Event::PeerOnline { .. } => "peer_online",
+            Event::PeerOffline { .. } => "peer_offline",
+        }
+    }
+
+    /// Whether the event is relevant enough to be sent to the webhooks that don't select the
+    /// events explicitly: only the links with the other servers going down are reported, not the
+    /// clients going offline, and only the first connection of the peers.
+    pub fn is_notable(&self) -> bool {
+        match self {
+            Event::DatabaseNotification { .. } | Event::ReconciliationCompleted => false,
+            Event::PeerOnline {
+                first_connection, ..
+            } => *first_connection,
+            Event::PeerOffline { kind, .. } => *kind == PeerKind::Server,
+            _ => true,
+        }
+    }
+
+    /// Format the event as a message of a Server-Sent Events stream.
+    pub fn to_sse(&self) -> String {
+        let data = serde_json::to_string(self).unwrap_or_default();
+        format!("event: {}\ndata: {}\n\n", self.name(), data)
+    }
+}
+
+/// Broadcast the events of this server to all the subscribers.
+#[derive(Debug, Default)]
+pub struct EventBus {
+    /// The channels of the subscribers.
+    subscribers: Mutex<Vec<mpsc::UnboundedSender<Event>>>,
+}
+
+impl EventBus {
+    /// Subscribe to all the future events. The subscription ends when the receiver is dropped.
+    pub fn subscribe(&self) -> mpsc::UnboundedReceiver<Event> {
+        let (tx, rx) = mpsc::unbounded();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Send an event to all the subscribers, forgetting the ones that are gone.
+    pub fn publish(&self, event: Event) {
+        debug!("Publishing event: {:?}", event);
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|s| s.unbounded_send(event.clone()).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    #[test]
+    fn test_to_sse() {
+        let event = Event::ClientAdded {
+            name: "client1".to_string(),
+        };
+        assert_eq!(
+            event.to_sse(),
+            "event: client_added\ndata: {\"type\":\"client_added\",\"name\":\"client1\"}\n\n"
+        );
+        assert_eq!(
+            Event::ReconciliationCompleted.to_sse(),
+            "event: reconciliation_completed\ndata: {\"type\":\"reconciliation_completed\"}\n\n"
+        );
+    }
+
+    #[test]
+    fn test_is_notable() {
+        let online = |first_connection| Event::PeerOnline {
+            name: Some("client1".to_string()),
+            public_key: "client1-key".to_string(),
+            kind: PeerKind::Client,
+            first_connection,
+        };
+        assert!(online(true).is_notable());
+        // the reconnections are sent only to the webhooks that select them
+        assert!(!online(false).is_notable());
+        assert_eq!(online(false).name(), "peer_online");
+    }
+
+    #[tokio::test]
+    async fn test_event_bus() {
+        let bus = EventBus::default();
+        let mut rx1 = bus.subscribe();
+        let rx2 = bus.subscribe();
+        drop(rx2);
+        bus.publish(Event::ReconciliationCompleted);
+        assert_eq!(bus.subscribers.lock().unwrap().len(), 1);
+        let event = rx1.next().await.unwrap();
+        assert_eq!(event.name(), "reconciliation_completed");
+    }
+}
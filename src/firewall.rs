@@ -0,0 +1,230 @@
+use failure::{bail, Error};
+use std::net::IpAddr;
+use std::process::Stdio;
+use tokio::net::process::Command;
+use tokio_postgres::Client;
+
+use crate::config::ServerConfig;
+use crate::schema;
+
+/// The commands managing the firewall of each IP family, with whether the family is IPv4.
+const IPTABLES: [(&str, bool); 2] = [("iptables", true), ("ip6tables", false)];
+
+/// Update the firewall for the clients that use this server as exit node, enabling the forwarding,
+/// accepting their traffic in the `FORWARD` chain and masquerading it on the uplink device. Only the
+/// IP families with some exit clients are configured, a missing `iptables` or `ip6tables` is
+/// reported without failing.
+pub async fn update_firewall(config: &ServerConfig, client: &Client) -> Result<(), Error> {
+    let addresses: Vec<_> = schema::get_clients(client, Some(&config.name))
+        .await?
+        .into_iter()
+        .filter(|c| c.exit_node)
+        .flat_map(|c| c.addresses())
+        .collect();
+    if addresses.iter().any(|a| a.is_ipv4()) {
+        enable_forwarding("net.ipv4.ip_forward").await;
+    }
+    if addresses.iter().any(|a| a.is_ipv6()) {
+        enable_forwarding("net.ipv6.conf.all.forwarding").await;
+    }
+    let uplink = match &config.uplink_device {
+        Some(uplink) => uplink,
+        None => {
+            if !addresses.is_empty() {
+                warn!("Some clients use this server as exit node, but uplink_device is not set");
+            }
+            return Ok(());
+        }
+    };
+    let chain = exit_chain(config);
+    for (iptables, ipv4) in &IPTABLES {
+        let family: Vec<_> = addresses.iter().filter(|a| a.is_ipv4() == *ipv4).collect();
+        if family.is_empty() {
+            // remove the rules of the clients that are not exit nodes anymore, if any
+            for table in &["nat", "filter"] {
+                let _ = iptables_command(iptables, table, &["-F", &chain], true).await;
+            }
+            continue;
+        }
+        match update_rules(iptables, &chain, &config.device_name, uplink, &family).await {
+            Ok(_) => {}
+            Err(e) if is_not_found(&e) => warn!(
+                "{} is not available, the traffic of the exit clients is not forwarded",
+                iptables
+            ),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Replace the rules of the chains of this server with the ones of the exit clients of an IP
+/// family, making sure the chains are used.
+async fn update_rules(
+    iptables: &str,
+    chain: &str,
+    device: &str,
+    uplink: &str,
+    addresses: &[&IpAddr],
+) -> Result<(), Error> {
+    for (table, parent) in &[("nat", "POSTROUTING"), ("filter", "FORWARD")] {
+        // the chain may already exist
+        iptables_command(iptables, table, &["-N", chain], true).await?;
+        if !iptables_command(iptables, table, &["-C", parent, "-j", chain], true).await? {
+            // the forwarding is accepted before the other rules, which may drop it
+            iptables_command(iptables, table, &["-I", parent, "-j", chain], false).await?;
+        }
+        iptables_command(iptables, table, &["-F", chain], false).await?;
+    }
+    for address in addresses {
+        for (table, rule) in exit_rules(device, uplink, address) {
+            let mut args = vec!["-A", chain];
+            args.extend(rule.iter().map(|a| a.as_str()));
+            iptables_command(iptables, table, &args, false).await?;
+        }
+        info!("Forwarding the traffic of {} through {}", address, uplink);
+    }
+    Ok(())
+}
+
+/// The rules for an exit client, with their table: its traffic is masqueraded on the uplink device
+/// and forwarded from the wireguard device to the uplink one, and only the replies are forwarded
+/// back.
+fn exit_rules(device: &str, uplink: &str, address: &IpAddr) -> Vec<(&'static str, Vec<String>)> {
+    let address = address.to_string();
+    let rules = vec![
+        (
+            "nat",
+            vec!["-s", &address, "-o", uplink, "-j", "MASQUERADE"],
+        ),
+        (
+            "filter",
+            vec!["-s", &address, "-i", device, "-o", uplink, "-j", "ACCEPT"],
+        ),
+        (
+            "filter",
+            vec![
+                "-d",
+                &address,
+                "-i",
+                uplink,
+                "-o",
+                device,
+                "-m",
+                "conntrack",
+                "--ctstate",
+                "RELATED,ESTABLISHED",
+                "-j",
+                "ACCEPT",
+            ],
+        ),
+    ];
+    rules
+        .into_iter()
+        .map(|(table, rule)| (table, rule.into_iter().map(String::from).collect()))
+        .collect()
+}
+
+/// The name of the chains of the `nat` and `filter` tables with the rules of this server.
+fn exit_chain(config: &ServerConfig) -> String {
+    format!("{}-exit", config.device_name)
+}
+
+/// Run a command on a table of `iptables` or `ip6tables`, returning whether it succeeded. If
+/// `may_fail` is false an error is returned on failure.
+async fn iptables_command(
+    iptables: &str,
+    table: &str,
+    args: &[&str],
+    may_fail: bool,
+) -> Result<bool, Error> {
+    let mut command = Command::new(iptables);
+    command.args(&["-t", table]).args(args);
+    if may_fail {
+        command.stdout(Stdio::null()).stderr(Stdio::null());
+    }
+    let child = command.spawn()?.await?;
+    if !child.success() && !may_fail {
+        bail!(
+            "{} -t {} {:?} failed: exit code {:?}",
+            iptables,
+            table,
+            args,
+            child.code()
+        );
+    }
+    Ok(child.success())
+}
+
+/// Whether the error is caused by a missing command.
+fn is_not_found(error: &Error) -> bool {
+    match error.downcast_ref::<std::io::Error>() {
+        Some(e) => e.kind() == std::io::ErrorKind::NotFound,
+        None => false,
+    }
+}
+
+/// Enable the forwarding of the packets using `sysctl`. Failures are not fatal since the forwarding
+/// may be already enabled, for example by docker.
+async fn enable_forwarding(key: &str) {
+    let setting = format!("{}=1", key);
+    match Command::new("sysctl")
+        .args(&["-w", &setting])
+        .output()
+        .await
+    {
+        Ok(output) if output.status.success() => debug!("Forwarding enabled with {}", setting),
+        Ok(output) => warn!("Failed to enable the forwarding: {:?}", output),
+        Err(e) => warn!("Failed to enable the forwarding: {:?}", e),
+    }
+}
+
+/// Remove the rules of this server from the firewall, synchronously.
+pub fn unsetup_firewall(config: &ServerConfig) {
+    let chain = exit_chain(config);
+    for (iptables, _) in &IPTABLES {
+        for (table, parent) in &[("nat", "POSTROUTING"), ("filter", "FORWARD")] {
+            for args in &[
+                vec!["-D", parent, "-j", &chain],
+                vec!["-F", &chain],
+                vec!["-X", &chain],
+            ] {
+                let _ = std::process::Command::new(iptables)
+                    .args(&["-t", table])
+                    .args(args)
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null())
+                    .status();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exit_rules() {
+        let address = IpAddr::from([10, 0, 1, 2]);
+        let rules: Vec<_> = exit_rules("wg0", "eth0", &address)
+            .into_iter()
+            .map(|(table, rule)| format!("{}: {}", table, rule.join(" ")))
+            .collect();
+        assert_eq!(
+            rules,
+            vec![
+                "nat: -s 10.0.1.2 -o eth0 -j MASQUERADE",
+                "filter: -s 10.0.1.2 -i wg0 -o eth0 -j ACCEPT",
+                "filter: -d 10.0.1.2 -i eth0 -o wg0 -m conntrack --ctstate RELATED,ESTABLISHED -j ACCEPT",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_is_not_found() {
+        let missing = std::process::Command::new("/nonexistent/iptables").spawn();
+        assert!(is_not_found(&Error::from(missing.unwrap_err())));
+        assert!(!is_not_found(&failure::format_err!("exit code 1")));
+    }
+}
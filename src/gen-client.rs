@@ -1,28 +1,68 @@
 //! Command line tool for generating the configuration file of a client.
 //!
-//! Usage: gen-client username [private key]
+//! Usage: gen-client username [private key] [--server name] [--format format]
 //!
 //! The username must be attached to a server. If the private key is not provided, it must be added
-//! manually to the produced configuration.
+//! manually to the produced configuration, and so must the preshared keys of the connections using
+//! one, except for the connection with the server this tool is run on. If the client is attached to more servers, the entire
+//! network is routed through the preferred one, which can be changed with `--server`.
+//!
+//! The configuration is generated for wg-quick by default, `--format` selects another one among
+//! `networkmanager`, `networkd`, `openwrt`, `mikrotik` and `json`.
 
 #[macro_use]
 extern crate lazy_static;
 #[macro_use]
 extern crate log;
 
+use crate::client_config::ClientFormat;
+use crate::schema::DnsLabel;
 use failure::Error;
+use std::collections::BTreeMap;
 
+pub mod client_config;
 pub mod config;
+pub mod endpoints;
+pub mod preshared_keys;
+pub mod rotation;
 pub mod schema;
+pub mod topology;
+pub mod traffic;
 pub mod wireguard;
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
     env_logger::init();
 
-    let args: Vec<_> = std::env::args().collect();
+    let mut args: Vec<_> = std::env::args().collect();
+    // the preferred server is removed from the positional arguments
+    let primary = match args.iter().position(|a| a == "--server") {
+        Some(index) if index + 1 < args.len() => {
+            let primary = args.remove(index + 1);
+            args.remove(index);
+            Some(primary)
+        }
+        _ => None,
+    };
+    let format = match args.iter().position(|a| a == "--format") {
+        Some(index) if index + 1 < args.len() => {
+            let format = args.remove(index + 1);
+            args.remove(index);
+            match format.parse::<ClientFormat>() {
+                Ok(format) => format,
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        _ => ClientFormat::default(),
+    };
     if args.len() < 2 || args.iter().any(|a| *a == "--help" || *a == "-h") {
-        eprintln!("Usage: {} client [private key]", args[0]);
+        eprintln!(
+            "Usage: {} client [private key] [--server name] [--format format]",
+            args[0]
+        );
         std::process::exit(1);
     }
 
@@ -33,9 +73,32 @@ async fn main() -> Result<(), Error> {
     let client = schema::connect(&config.database_url).await?;
     debug!("Connected to the database");
 
-    let username = args[1].to_string();
+    let username = match args[1].parse::<DnsLabel>() {
+        Ok(username) => username,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
     let private_key = args.get(2).map(|s| s.to_string());
-    let conf = wireguard::gen_client_config(&config, &client, username, private_key).await;
+    // the preshared key of the client is known only if this tool runs on its server
+    let mut preshared_keys = BTreeMap::new();
+    if let Some(key) = preshared_keys::read(&config)
+        .await?
+        .remove(username.as_str())
+    {
+        preshared_keys.insert(config.name.clone(), key);
+    }
+    let conf = wireguard::gen_client_config(
+        &config,
+        &client,
+        username,
+        private_key,
+        &preshared_keys,
+        primary,
+        format,
+    )
+    .await;
 
     match conf {
         Ok(conf) => println!("{}", conf),
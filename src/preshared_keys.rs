@@ -0,0 +1,113 @@
+use failure::{bail, Error};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use tempfile::NamedTempFile;
+use tokio::net::process::Command;
+
+use crate::config::ServerConfig;
+use crate::schema::ClientConnection;
+
+/// The default path of the file with the preshared keys of the clients.
+const DEFAULT_PRESHARED_KEYS_FILE: &str = "preshared_keys.yaml";
+
+/// The path of the file where this server keeps the preshared keys of its clients.
+fn path(config: &ServerConfig) -> PathBuf {
+    config
+        .preshared_keys_file
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_PRESHARED_KEYS_FILE))
+}
+
+/// Read the preshared keys of the clients of this server, by name of the client. The keys are never
+/// stored in the database, only in a file readable by the server.
+pub async fn read(config: &ServerConfig) -> Result<BTreeMap<String, String>, Error> {
+    let path = path(config);
+    if !path.exists() {
+        return Ok(BTreeMap::new());
+    }
+    let content = tokio::fs::read(path.clone()).await?;
+    if content.is_empty() {
+        return Ok(BTreeMap::new());
+    }
+    Ok(serde_yaml::from_slice(&content)?)
+}
+
+/// Generate the missing preshared keys of the connections of this server that use one, returning
+/// the keys of all the clients. A key can be generated again removing it from the file.
+pub async fn ensure(
+    config: &ServerConfig,
+    connections: &[ClientConnection],
+) -> Result<BTreeMap<String, String>, Error> {
+    let mut keys = read(config).await?;
+    let mut changed = false;
+    for conn in connections {
+        if conn.server != config.name
+            || !conn.use_preshared_key
+            || keys.contains_key(&conn.client.name)
+        {
+            continue;
+        }
+        keys.insert(conn.client.name.clone(), gen_preshared_key().await?);
+        info!(
+            "Generated the preshared key of the connection with {}",
+            conn.client.name
+        );
+        changed = true;
+    }
+    if changed {
+        write(&path(config), &serde_yaml::to_string(&keys)?).await?;
+    }
+    Ok(keys)
+}
+
+/// Replace the file of the keys atomically. The temporary file is readable only by its owner, and
+/// so is the file once renamed.
+async fn write(path: &Path, content: &str) -> Result<(), Error> {
+    let dir = match path.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+    let tmpfile = NamedTempFile::new_in(dir)?;
+    tokio::fs::write(tmpfile.path().to_path_buf(), content.as_bytes()).await?;
+    tmpfile.persist(path)?;
+    Ok(())
+}
+
+/// Generate a new preshared key using `wg genpsk`.
+async fn gen_preshared_key() -> Result<String, Error> {
+    let output = Command::new("wg").arg("genpsk").output().await?;
+    if !output.status.success() {
+        bail!("Failed to generate a preshared key: {:?}", output);
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn keys_config(path: &Path) -> ServerConfig {
+        let mut config = test_config("alpha", "");
+        config.preshared_keys_file = Some(path.to_path_buf());
+        config
+    }
+
+    #[tokio::test]
+    async fn test_read_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = keys_config(&dir.path().join("keys.yaml"));
+        // a missing file has no keys
+        assert!(read(&config).await.unwrap().is_empty());
+        let mut keys = BTreeMap::new();
+        keys.insert("client".to_string(), "client-psk".to_string());
+        write(&path(&config), &serde_yaml::to_string(&keys).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(read(&config).await.unwrap(), keys);
+        // the keys are readable only by the server
+        let metadata = std::fs::metadata(path(&config)).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o077, 0);
+    }
+}
@@ -0,0 +1,117 @@
+use failure::{bail, Error};
+use std::process::Stdio;
+use tokio::io::AsyncWriteExt;
+use tokio::net::process::Command;
+use tokio_postgres::Client;
+
+use crate::config::ServerConfig;
+use crate::schema;
+use crate::schema::Server;
+use crate::topology::Topology;
+
+/// Stage the new key of this server, or complete its rotation.
+///
+/// The rotation starts when `pending_private_key` is set in the configuration file: its public key
+/// is staged in the database, where the other servers accept it alongside the old one and
+/// acknowledge it. When all the servers linked with this one have acknowledged it, this server
+/// switches to the new private key, the public key in the database is replaced and all the
+/// connections of this server are flagged as needing a new client configuration.
+pub async fn update_key_rotation(config: &ServerConfig, client: &Client) -> Result<(), Error> {
+    let pending_private_key = match &config.pending_private_key {
+        Some(key) => key,
+        None => return Ok(()),
+    };
+    let pending_public_key = public_key_of(pending_private_key).await?;
+    let servers = schema::get_servers(client).await?;
+    let server = servers
+        .iter()
+        .find(|s| s.name == config.name)
+        .expect("Server is not registered in the db");
+    // the rotation has already been completed
+    if server.public_key == pending_public_key {
+        return Ok(());
+    }
+    if server.pending_public_key.as_ref() != Some(&pending_public_key) {
+        info!("Staging the new public key {}", pending_public_key);
+        schema::set_server_pending_key(client, &config.name, &pending_public_key).await?;
+        return Ok(());
+    }
+    let links = schema::get_server_links(client).await?;
+    let topology = Topology::new(&servers, &links);
+    let acks = schema::get_server_key_acks(client).await?;
+    let missing: Vec<_> = topology
+        .neighbors(&config.name)
+        .into_iter()
+        .filter(|name| {
+            !acks.iter().any(|ack| {
+                ack.server == config.name
+                    && ack.acked_by == *name
+                    && ack.public_key == pending_public_key
+            })
+        })
+        .collect();
+    if !missing.is_empty() {
+        debug!("New key not acknowledged yet by {:?}", missing);
+        return Ok(());
+    }
+    info!("New key acknowledged by all the servers, switching to it");
+    schema::complete_server_key_rotation(client, &config.name).await?;
+    Ok(())
+}
+
+/// Acknowledge the pending keys of the servers linked with this one, after the configuration of
+/// the device accepting them has been applied.
+pub async fn acknowledge_keys(config: &ServerConfig, client: &Client) -> Result<(), Error> {
+    let servers = schema::get_servers(client).await?;
+    let links = schema::get_server_links(client).await?;
+    let topology = Topology::new(&servers, &links);
+    let neighbors = topology.neighbors(&config.name);
+    let acks = schema::get_server_key_acks(client).await?;
+    for server in &servers {
+        let pending_public_key = match &server.pending_public_key {
+            Some(key) if neighbors.contains(&server.name.as_str()) => key,
+            _ => continue,
+        };
+        let acked = acks.iter().any(|ack| {
+            ack.server == server.name
+                && ack.acked_by == config.name
+                && ack.public_key == *pending_public_key
+        });
+        if !acked {
+            info!("Acknowledging the new key of {}", server.name);
+            schema::ack_server_key(client, &server.name, &config.name, pending_public_key).await?;
+        }
+    }
+    Ok(())
+}
+
+/// The private key this server is currently using: the pending one if the rotation has been
+/// completed, otherwise the one in the configuration file.
+pub async fn active_private_key(config: &ServerConfig, server: &Server) -> Result<String, Error> {
+    if let Some(pending_private_key) = &config.pending_private_key {
+        if public_key_of(pending_private_key).await? == server.public_key {
+            return Ok(pending_private_key.clone());
+        }
+    }
+    Ok(config.private_key.clone())
+}
+
+/// Compute the public key of a private key using `wg pubkey`.
+async fn public_key_of(private_key: &str) -> Result<String, Error> {
+    let mut child = Command::new("wg")
+        .arg("pubkey")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    if let Some(stdin) = child.stdin().as_mut() {
+        stdin.write_all(private_key.as_bytes()).await?;
+    }
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        bail!(
+            "Failed to compute the public key: {:?}",
+            output.status.code()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
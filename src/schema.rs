@@ -1,17 +1,92 @@
-use failure::Error;
+use chrono::NaiveDate;
+use failure::{bail, Error};
 use futures::channel::mpsc;
 use futures::stream;
 use futures::FutureExt;
 use futures_util::StreamExt;
 use futures_util::TryStreamExt;
+use std::fmt;
 use std::net::IpAddr;
 use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio_postgres::types::ToSql;
 use tokio_postgres::{AsyncMessage, NoTls, Row};
 
 /// The schema of the database.
 const SCHEMA: &str = include_str!("schema.sql");
 
+/// After how many seconds without an handshake a peer is considered offline. Wireguard renews the
+/// handshake every 2 minutes while the connection is in use.
+const ONLINE_TIMEOUT: u64 = 180;
+
+/// The columns of the `servers` table to select for building a `Server` with `Server::from_sql`.
+const SERVER_COLUMNS: &str = "servers.name, host(servers.subnet), masklen(servers.subnet), \
+                              host(servers.address), host(servers.public_address), \
+                              servers.public_port, servers.public_key, servers.hub, \
+                              servers.public_hostname, servers.keepalive, servers.mtu, \
+                              host(servers.secondary_subnet), masklen(servers.secondary_subnet), \
+                              host(servers.secondary_address), \
+                              servers.pending_public_key, servers.dns";
+
+/// The number of columns in `SERVER_COLUMNS`.
+const SERVER_COLUMNS_COUNT: usize = 16;
+
+/// The condition on the `clients` and `connections` tables for selecting only the connections
+/// currently allowed, excluding the ones not yet valid or expired.
+const VALID_CONNECTIONS: &str = "(clients.valid_from IS NULL OR clients.valid_from <= now()) \
+     AND (clients.valid_until IS NULL OR clients.valid_until > now()) \
+     AND (connections.valid_from IS NULL OR connections.valid_from <= now()) \
+     AND (connections.valid_until IS NULL OR connections.valid_until > now())";
+
+/// The columns of the `clients` table to select for building a `Client` with `Client::from_sql`.
+const CLIENT_COLUMNS: &str = "clients.name, clients.public_key, clients.active_server, \
+                              clients.keepalive, clients.mtu, clients.pending_public_key, \
+                              clients.push_dns";
+
+/// The number of columns in `CLIENT_COLUMNS`.
+const CLIENT_COLUMNS_COUNT: usize = 7;
+
+/// The name of a server or of a client, a valid DNS label (RFC 1123) since it's used as
+/// `<name>.<base_domain>`.
+#[derive(Debug, Clone, Eq, Ord, PartialOrd, PartialEq, Hash)]
+pub struct DnsLabel(String);
+
+impl DnsLabel {
+    /// The label as a string.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for DnsLabel {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        if name.is_empty() || name.len() > 63 {
+            bail!(
+                "The name {:?} must be between 1 and 63 characters long",
+                name
+            );
+        }
+        if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            bail!(
+                "The name {:?} can contain only letters, digits and dashes",
+                name
+            );
+        }
+        if name.starts_with('-') || name.ends_with('-') {
+            bail!("The name {:?} cannot start or end with a dash", name);
+        }
+        Ok(DnsLabel(name.to_string()))
+    }
+}
+
+impl fmt::Display for DnsLabel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
 /// A server inside the wireguard network.
 #[derive(Debug, Clone, Eq, Ord, PartialOrd, PartialEq)]
 pub struct Server {
@@ -23,28 +98,89 @@ pub struct Server {
     pub subnet_len: u8,
     /// The address of the server in its subnet.
     pub address: IpAddr,
-    /// The address with which the server can be reached from the outside.
-    pub public_address: IpAddr,
+    /// The address with which the server can be reached from the outside, if it's static.
+    pub public_address: Option<IpAddr>,
     /// The port bound to wireguard.
     pub public_port: u16,
     /// The public key of the server.
     pub public_key: String,
+    /// Whether the server is a hub, linked with all the other servers.
+    pub hub: bool,
+    /// The hostname with which the server can be reached from the outside, it takes precedence over
+    /// the public address.
+    pub public_hostname: Option<String>,
+    /// The keepalive of the peers of the server, overriding the one of the configuration file.
+    pub keepalive: Option<u32>,
+    /// The MTU of the wireguard interface of the server.
+    pub mtu: Option<u32>,
+    /// The subnet of the other IP family managed by the server, if any.
+    pub secondary_subnet_addr: Option<IpAddr>,
+    /// The length of the secondary subnet.
+    pub secondary_subnet_len: Option<u8>,
+    /// The address of the server in its secondary subnet.
+    pub secondary_address: Option<IpAddr>,
+    /// The new public key of the server, during a key rotation.
+    pub pending_public_key: Option<String>,
+    /// Whether the server is an authoritative DNS server of the zone.
+    pub dns: bool,
 }
 
 impl Server {
     /// Build a `Server` from a row returned by an SQL query of the form:
-    ///     SELECT name, host(subnet), masklen(subnet), host(servers.address),
-    ///            host(public_address), public_port, public_key
-    ///     FROM servers
+    ///     SELECT <SERVER_COLUMNS> FROM servers
     fn from_sql(row: &Row, start_index: usize) -> Server {
         Server {
             name: row.get(start_index),
             subnet_addr: IpAddr::from_str(row.get(start_index + 1)).unwrap(),
             subnet_len: row.get::<_, i32>(start_index + 2) as u8,
             address: IpAddr::from_str(row.get(start_index + 3)).unwrap(),
-            public_address: IpAddr::from_str(row.get(start_index + 4)).unwrap(),
+            public_address: row
+                .get::<_, Option<&str>>(start_index + 4)
+                .map(|a| IpAddr::from_str(a).unwrap()),
             public_port: row.get::<_, i32>(start_index + 5) as u16,
             public_key: row.get(start_index + 6),
+            hub: row.get(start_index + 7),
+            public_hostname: row.get(start_index + 8),
+            keepalive: row.get::<_, Option<i32>>(start_index + 9).map(|k| k as u32),
+            mtu: row
+                .get::<_, Option<i32>>(start_index + 10)
+                .map(|m| m as u32),
+            secondary_subnet_addr: row
+                .get::<_, Option<&str>>(start_index + 11)
+                .map(|a| IpAddr::from_str(a).unwrap()),
+            secondary_subnet_len: row.get::<_, Option<i32>>(start_index + 12).map(|l| l as u8),
+            secondary_address: row
+                .get::<_, Option<&str>>(start_index + 13)
+                .map(|a| IpAddr::from_str(a).unwrap()),
+            pending_public_key: row.get(start_index + 14),
+            dns: row.get(start_index + 15),
+        }
+    }
+
+    /// The subnets managed by the server, one for each IP family.
+    pub fn subnets(&self) -> Vec<(IpAddr, u8)> {
+        let mut subnets = vec![(self.subnet_addr, self.subnet_len)];
+        if let (Some(addr), Some(len)) = (self.secondary_subnet_addr, self.secondary_subnet_len) {
+            subnets.push((addr, len));
+        }
+        subnets
+    }
+
+    /// The addresses of the server in its subnets, one for each IP family.
+    pub fn addresses(&self) -> Vec<IpAddr> {
+        let mut addresses = vec![self.address];
+        addresses.extend(self.secondary_address);
+        addresses
+    }
+
+    /// The host with which the server can be reached from the outside: its hostname if present,
+    /// otherwise its public address. `None` if the server has neither, which is not allowed by the
+    /// schema but may happen with the rows added before the constraint.
+    pub fn public_host(&self) -> Option<String> {
+        match (&self.public_hostname, &self.public_address) {
+            (Some(hostname), _) => Some(hostname.clone()),
+            (None, Some(address)) => Some(address.to_string()),
+            (None, None) => None,
         }
     }
 }
@@ -56,6 +192,32 @@ pub struct Client {
     pub name: String,
     /// The public key of the client.
     pub public_key: String,
+    /// The server currently routing all the addresses of the client, if known.
+    pub active_server: Option<String>,
+    /// The keepalive of the peers in the client configuration.
+    pub keepalive: Option<u32>,
+    /// The MTU of the wireguard interface of the client.
+    pub mtu: Option<u32>,
+    /// The new public key of the client, during a key rotation.
+    pub pending_public_key: Option<String>,
+    /// Whether the DNS settings are included in the client configuration.
+    pub push_dns: bool,
+}
+
+impl Client {
+    /// Build a `Client` from a row returned by an SQL query of the form:
+    ///     SELECT <CLIENT_COLUMNS> FROM clients
+    fn from_sql(row: &Row, start_index: usize) -> Client {
+        Client {
+            name: row.get(start_index),
+            public_key: row.get(start_index + 1),
+            active_server: row.get(start_index + 2),
+            keepalive: row.get::<_, Option<i32>>(start_index + 3).map(|k| k as u32),
+            mtu: row.get::<_, Option<i32>>(start_index + 4).map(|m| m as u32),
+            pending_public_key: row.get(start_index + 5),
+            push_dns: row.get(start_index + 6),
+        }
+    }
 }
 
 /// The authorization for a user to connect to a server, including its private address.
@@ -67,6 +229,29 @@ pub struct ClientConnection {
     pub client: Client,
     /// The address of the client, connecting to that server.
     pub address: IpAddr,
+    /// The priority of the connection, lower values are preferred.
+    pub priority: i32,
+    /// Whether all the traffic of the client goes through the server.
+    pub exit_node: bool,
+    /// The address of the other IP family of the client, if any.
+    pub secondary_address: Option<IpAddr>,
+    /// Whether the connection is protected by a preshared key, kept only by the server.
+    pub use_preshared_key: bool,
+    /// Whether both the client and the connection are enabled.
+    pub enabled: bool,
+    /// Why the client or the connection has been disabled, if specified.
+    pub disabled_reason: Option<String>,
+    /// The UNIX timestamp of when the client or the connection has been disabled.
+    pub disabled_at: Option<u64>,
+}
+
+impl ClientConnection {
+    /// The addresses of the client for this connection, one for each IP family.
+    pub fn addresses(&self) -> Vec<IpAddr> {
+        let mut addresses = vec![self.address];
+        addresses.extend(self.secondary_address);
+        addresses
+    }
 }
 
 /// The details of the connection to a server.
@@ -76,6 +261,115 @@ pub struct ServerConnection {
     pub server: Server,
     /// The address of the client, connecting to that server.
     pub address: IpAddr,
+    /// The priority of the connection, lower values are preferred.
+    pub priority: i32,
+    /// Whether all the traffic of the client goes through the server.
+    pub exit_node: bool,
+    /// The address of the other IP family of the client, if any.
+    pub secondary_address: Option<IpAddr>,
+    /// Whether the connection is protected by a preshared key, kept only by the server.
+    pub use_preshared_key: bool,
+    /// Whether the client needs a new configuration, after the key of the server changed.
+    pub config_outdated: bool,
+}
+
+impl ServerConnection {
+    /// The addresses of the client for this connection, one for each IP family.
+    pub fn addresses(&self) -> Vec<IpAddr> {
+        let mut addresses = vec![self.address];
+        addresses.extend(self.secondary_address);
+        addresses
+    }
+}
+
+/// A subnet routed through a client, reachable through the server of the connection.
+#[derive(Debug, Clone, Eq, Ord, PartialOrd, PartialEq)]
+pub struct RoutedSubnet {
+    /// The address of the subnet.
+    pub subnet_addr: IpAddr,
+    /// The length of the subnet.
+    pub subnet_len: u8,
+    /// The name of the server routing the subnet.
+    pub server: String,
+    /// The name of the client behind which the subnet is.
+    pub client: String,
+}
+
+/// The live status of a peer of a wireguard device.
+#[derive(Debug, Clone, Eq, Ord, PartialOrd, PartialEq)]
+pub struct PeerStatus {
+    /// The public key of the peer.
+    pub public_key: String,
+    /// The address from which the peer has been seen for the last time.
+    pub endpoint: Option<String>,
+    /// The UNIX timestamp of the most recent handshake, if any.
+    pub latest_handshake: Option<u64>,
+    /// The number of bytes received from this peer.
+    pub transfer_rx: u64,
+    /// The number of bytes sent to this peer.
+    pub transfer_tx: u64,
+}
+
+impl PeerStatus {
+    /// Whether the peer completed an handshake recently.
+    pub fn is_online(&self) -> bool {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        match self.latest_handshake {
+            Some(handshake) => now.saturating_sub(handshake) < ONLINE_TIMEOUT,
+            None => false,
+        }
+    }
+}
+
+/// The acknowledgement of the pending key of a server by another server.
+#[derive(Debug, Clone, Eq, Ord, PartialOrd, PartialEq)]
+pub struct ServerKeyAck {
+    /// The name of the server rotating its key.
+    pub server: String,
+    /// The name of the server that accepts the new key.
+    pub acked_by: String,
+    /// The new key accepted.
+    pub public_key: String,
+}
+
+/// An extra DNS record of the network, stored in the `dns_records` table.
+#[derive(Debug, Clone, Eq, Ord, PartialOrd, PartialEq)]
+pub struct DnsRecord {
+    /// The name of the record, relative to the base domain.
+    pub name: String,
+    /// The type of the record: `CNAME`, `A`, `AAAA`, `TXT` or `SRV`.
+    pub record_type: String,
+    /// The value of the record, targets are relative to the base domain.
+    pub value: String,
+}
+
+/// The status of a peer as published in the database by a server.
+#[derive(Debug, Clone, Eq, Ord, PartialOrd, PartialEq)]
+pub struct PublishedPeerStatus {
+    /// The name of the server that published the status.
+    pub server: String,
+    /// The status of the peer, as seen by that server.
+    pub status: PeerStatus,
+    /// How many seconds ago the status has been published.
+    pub age: u64,
+}
+
+/// The traffic of a client through a server in a day.
+#[derive(Debug, Clone, Eq, Ord, PartialOrd, PartialEq)]
+pub struct TrafficUsage {
+    /// The day of the traffic, in the `YYYY-MM-DD` format.
+    pub day: String,
+    /// The name of the client.
+    pub client: String,
+    /// The name of the server.
+    pub server: String,
+    /// The number of bytes received from the client.
+    pub transfer_rx: u64,
+    /// The number of bytes sent to the client.
+    pub transfer_tx: u64,
 }
 
 /// Connect to the database, spawning the background task for managing the connection.
@@ -117,10 +411,7 @@ pub async fn create_schema(client: &tokio_postgres::Client) -> Result<(), Error>
 /// Retrieve a list of all the servers in the database.
 pub async fn get_servers(client: &tokio_postgres::Client) -> Result<Vec<Server>, Error> {
     let stmt = client
-        .prepare(
-            "SELECT name, host(subnet), masklen(subnet), host(address), host(public_address), public_port, public_key \
-             FROM servers",
-        )
+        .prepare(&format!("SELECT {} FROM servers", SERVER_COLUMNS))
         .await?;
     let rows = client.query(&stmt, &[]).await?;
     Ok(rows
@@ -129,19 +420,66 @@ pub async fn get_servers(client: &tokio_postgres::Client) -> Result<Vec<Server>,
         .collect())
 }
 
-/// Retrieve a list of all the clients allowed to connect to the specified server.
-/// If the specified server is `None`, all the clients are returned.
+/// Retrieve the list of the explicit links between the servers, as pairs of server names.
+pub async fn get_server_links(
+    client: &tokio_postgres::Client,
+) -> Result<Vec<(String, String)>, Error> {
+    let stmt = client
+        .prepare("SELECT server_a, server_b FROM server_links")
+        .await?;
+    let rows = client.query(&stmt, &[]).await?;
+    Ok(rows
+        .into_iter()
+        .map(|row| (row.get(0), row.get(1)))
+        .collect())
+}
+
+/// Retrieve a list of all the clients allowed to connect to the specified server, excluding the
+/// connections not yet valid, expired or disabled.
+/// If the specified server is `None`, all the connections of all the clients are returned. The
+/// connections are sorted by priority.
 pub async fn get_clients<S: AsRef<str>>(
     client: &tokio_postgres::Client,
     server: Option<S>,
 ) -> Result<Vec<ClientConnection>, Error> {
-    let mut query = "SELECT server, name, public_key, host(address) \
-                     FROM connections \
-                     JOIN clients ON client = name"
-        .to_string();
+    query_clients(client, server, false).await
+}
+
+/// Retrieve a list of all the connections of all the clients, including the disabled ones. The
+/// connections not yet valid or expired are excluded.
+pub async fn get_all_clients(
+    client: &tokio_postgres::Client,
+) -> Result<Vec<ClientConnection>, Error> {
+    query_clients(client, None::<&str>, true).await
+}
+
+/// Retrieve the connections of the clients, optionally only of a server and including the disabled
+/// ones.
+async fn query_clients<S: AsRef<str>>(
+    client: &tokio_postgres::Client,
+    server: Option<S>,
+    include_disabled: bool,
+) -> Result<Vec<ClientConnection>, Error> {
+    let mut query = format!(
+        "SELECT connections.server, {}, host(connections.address), connections.priority, \
+         connections.exit_node, host(connections.secondary_address), \
+         connections.use_preshared_key, clients.enabled AND connections.enabled, \
+         CASE WHEN clients.enabled THEN connections.disabled_reason \
+              ELSE clients.disabled_reason END, \
+         extract(epoch from CASE WHEN clients.enabled THEN connections.disabled_at \
+                                 ELSE clients.disabled_at END)::BIGINT \
+         FROM connections \
+         JOIN clients ON connections.client = clients.name \
+         WHERE {}",
+        CLIENT_COLUMNS, VALID_CONNECTIONS
+    );
+    if !include_disabled {
+        query += " AND clients.enabled AND connections.enabled";
+    }
     if server.is_some() {
-        query += " WHERE server = $1";
+        query += " AND connections.server = $1";
     }
+    query += " ORDER BY connections.priority, connections.server, clients.name";
     // build the server name, the optional parameter of the query. Cannot build it conditionally
     // because a ref to it is needed when passing the parameter to `client.query`, which has a very
     // picky type.
@@ -160,34 +498,536 @@ pub async fn get_clients<S: AsRef<str>>(
         .into_iter()
         .map(|row| ClientConnection {
             server: row.get(0),
-            client: Client {
-                name: row.get(1),
-                public_key: row.get(2),
-            },
-            address: IpAddr::from_str(row.get(3)).unwrap(),
+            client: Client::from_sql(&row, 1),
+            address: IpAddr::from_str(row.get(CLIENT_COLUMNS_COUNT + 1)).unwrap(),
+            priority: row.get(CLIENT_COLUMNS_COUNT + 2),
+            exit_node: row.get(CLIENT_COLUMNS_COUNT + 3),
+            secondary_address: row
+                .get::<_, Option<&str>>(CLIENT_COLUMNS_COUNT + 4)
+                .map(|a| IpAddr::from_str(a).unwrap()),
+            use_preshared_key: row.get(CLIENT_COLUMNS_COUNT + 5),
+            enabled: row.get(CLIENT_COLUMNS_COUNT + 6),
+            disabled_reason: row.get(CLIENT_COLUMNS_COUNT + 7),
+            disabled_at: row
+                .get::<_, Option<i64>>(CLIENT_COLUMNS_COUNT + 8)
+                .map(|t| t as u64),
         })
         .collect())
 }
 
-/// Fetch the list of servers the client can connect to.
+/// Compute the time until the next change of validity of a client or a connection, if any.
+pub async fn get_next_validity_change(
+    client: &tokio_postgres::Client,
+) -> Result<Option<Duration>, Error> {
+    let stmt = client
+        .prepare(
+            "SELECT EXTRACT(EPOCH FROM MIN(time) - now())::FLOAT8 \
+             FROM (SELECT valid_from AS time FROM clients \
+                   UNION ALL SELECT valid_until FROM clients \
+                   UNION ALL SELECT valid_from FROM connections \
+                   UNION ALL SELECT valid_until FROM connections) times \
+             WHERE time > now()",
+        )
+        .await?;
+    let rows = client.query(&stmt, &[]).await?;
+    Ok(rows
+        .first()
+        .and_then(|row| row.get::<_, Option<f64>>(0))
+        .map(|secs| Duration::from_secs(secs.ceil() as u64)))
+}
+
+/// Fetch a client by name, if present.
+pub async fn get_client<S: Into<String>>(
+    client: &tokio_postgres::Client,
+    name: S,
+) -> Result<Option<Client>, Error> {
+    let stmt = client
+        .prepare(&format!(
+            "SELECT {} FROM clients WHERE clients.name = $1",
+            CLIENT_COLUMNS
+        ))
+        .await?;
+    let rows = client.query(&stmt, &[&name.into()]).await?;
+    Ok(rows.first().map(|row| Client::from_sql(row, 0)))
+}
+
+/// Fetch the list of servers the client can connect to, sorted by priority. The connections not yet
+/// valid, expired or disabled are excluded.
 pub async fn get_client_connections<S: Into<String>>(
     client: &tokio_postgres::Client,
     name: S,
 ) -> Result<Vec<ServerConnection>, Error> {
     let stmt = client
-        .prepare(
-            "SELECT name, host(subnet), masklen(subnet), host(servers.address), \
-             host(public_address), public_port, public_key, host(connections.address) \
+        .prepare(&format!(
+            "SELECT {}, host(connections.address), priority, exit_node, \
+             host(connections.secondary_address), connections.use_preshared_key, \
+             connections.config_outdated \
              FROM servers JOIN connections ON servers.name = connections.server \
-             WHERE connections.client = $1",
-        )
+             JOIN clients ON clients.name = connections.client \
+             WHERE connections.client = $1 AND {} \
+               AND clients.enabled AND connections.enabled \
+             ORDER BY priority, servers.name",
+            SERVER_COLUMNS, VALID_CONNECTIONS
+        ))
         .await?;
     let rows = client.query(&stmt, &[&name.into()]).await?;
     Ok(rows
         .into_iter()
         .map(|row| ServerConnection {
             server: Server::from_sql(&row, 0),
-            address: IpAddr::from_str(row.get(7)).unwrap(),
+            address: IpAddr::from_str(row.get(SERVER_COLUMNS_COUNT)).unwrap(),
+            priority: row.get(SERVER_COLUMNS_COUNT + 1),
+            exit_node: row.get(SERVER_COLUMNS_COUNT + 2),
+            secondary_address: row
+                .get::<_, Option<&str>>(SERVER_COLUMNS_COUNT + 3)
+                .map(|a| IpAddr::from_str(a).unwrap()),
+            use_preshared_key: row.get(SERVER_COLUMNS_COUNT + 4),
+            config_outdated: row.get(SERVER_COLUMNS_COUNT + 5),
+        })
+        .collect())
+}
+
+/// Retrieve a list of all the subnets routed through the clients.
+pub async fn get_routed_subnets(
+    client: &tokio_postgres::Client,
+) -> Result<Vec<RoutedSubnet>, Error> {
+    let stmt = client
+        .prepare(
+            "SELECT host(subnet), masklen(subnet), server, client \
+             FROM routed_subnets \
+             ORDER BY subnet",
+        )
+        .await?;
+    let rows = client.query(&stmt, &[]).await?;
+    Ok(rows
+        .into_iter()
+        .map(|row| RoutedSubnet {
+            subnet_addr: IpAddr::from_str(row.get(0)).unwrap(),
+            subnet_len: row.get::<_, i32>(1) as u8,
+            server: row.get(2),
+            client: row.get(3),
         })
         .collect())
 }
+
+/// Replace the status of the peers published by the specified server.
+pub async fn publish_peer_status<S: AsRef<str>>(
+    client: &tokio_postgres::Client,
+    server: S,
+    peers: &[PeerStatus],
+) -> Result<(), Error> {
+    let server = server.as_ref().to_string();
+    let public_keys: Vec<_> = peers.iter().map(|p| p.public_key.clone()).collect();
+    let endpoints: Vec<_> = peers.iter().map(|p| p.endpoint.clone()).collect();
+    let latest_handshakes: Vec<_> = peers
+        .iter()
+        .map(|p| p.latest_handshake.map(|h| h as i64))
+        .collect();
+    let transfers_rx: Vec<_> = peers.iter().map(|p| p.transfer_rx as i64).collect();
+    let transfers_tx: Vec<_> = peers.iter().map(|p| p.transfer_tx as i64).collect();
+    // The old peers are removed and the new ones are upserted in a single statement, so that the
+    // readers never see a partial status. A transaction cannot be used since the client is shared.
+    let stmt = client
+        .prepare(
+            "WITH removed AS ( \
+               DELETE FROM peer_status WHERE server = $1 AND NOT (public_key = ANY($2)) \
+             ) \
+             INSERT INTO peer_status \
+             (server, public_key, endpoint, latest_handshake, transfer_rx, transfer_tx, updated_at) \
+             SELECT $1, peer.*, now() \
+             FROM unnest($2::TEXT[], $3::TEXT[], $4::BIGINT[], $5::BIGINT[], $6::BIGINT[]) AS peer \
+             ON CONFLICT (server, public_key) DO UPDATE SET \
+             endpoint = EXCLUDED.endpoint, latest_handshake = EXCLUDED.latest_handshake, \
+             transfer_rx = EXCLUDED.transfer_rx, transfer_tx = EXCLUDED.transfer_tx, \
+             updated_at = EXCLUDED.updated_at",
+        )
+        .await?;
+    client
+        .execute(
+            &stmt,
+            &[
+                &server,
+                &public_keys,
+                &endpoints,
+                &latest_handshakes,
+                &transfers_rx,
+                &transfers_tx,
+            ],
+        )
+        .await?;
+    Ok(())
+}
+
+/// Retrieve the status of the peers published by all the servers.
+pub async fn get_peer_status(
+    client: &tokio_postgres::Client,
+) -> Result<Vec<PublishedPeerStatus>, Error> {
+    let stmt = client
+        .prepare(
+            "SELECT server, public_key, endpoint, latest_handshake, transfer_rx, transfer_tx, \
+             extract(epoch from now() - updated_at)::BIGINT \
+             FROM peer_status",
+        )
+        .await?;
+    let rows = client.query(&stmt, &[]).await?;
+    Ok(rows
+        .into_iter()
+        .map(|row| PublishedPeerStatus {
+            server: row.get(0),
+            status: PeerStatus {
+                public_key: row.get(1),
+                endpoint: row.get(2),
+                latest_handshake: row.get::<_, Option<i64>>(3).map(|h| h as u64),
+                transfer_rx: row.get::<_, i64>(4) as u64,
+                transfer_tx: row.get::<_, i64>(5) as u64,
+            },
+            age: row.get::<_, i64>(6).max(0) as u64,
+        })
+        .collect())
+}
+
+/// Account some traffic of a client through a server in the current day.
+pub async fn add_traffic<S1: AsRef<str>, S2: AsRef<str>>(
+    client: &tokio_postgres::Client,
+    server: S1,
+    name: S2,
+    transfer_rx: u64,
+    transfer_tx: u64,
+) -> Result<(), Error> {
+    let stmt = client
+        .prepare(
+            "INSERT INTO traffic (client, server, day, transfer_rx, transfer_tx) \
+             VALUES ($1, $2, current_date, $3, $4) \
+             ON CONFLICT (client, server, day) DO UPDATE SET \
+             transfer_rx = traffic.transfer_rx + EXCLUDED.transfer_rx, \
+             transfer_tx = traffic.transfer_tx + EXCLUDED.transfer_tx",
+        )
+        .await?;
+    client
+        .execute(
+            &stmt,
+            &[
+                &name.as_ref(),
+                &server.as_ref(),
+                &(transfer_rx as i64),
+                &(transfer_tx as i64),
+            ],
+        )
+        .await?;
+    Ok(())
+}
+
+/// Retrieve the traffic of the clients in the specified range of days, both ends included and in
+/// the `YYYY-MM-DD` format, checked with `validate_day`. By default the last 30 days are returned. If the name of a client is
+/// specified only its traffic is returned.
+pub async fn get_traffic(
+    client: &tokio_postgres::Client,
+    from: Option<String>,
+    to: Option<String>,
+    name: Option<String>,
+) -> Result<Vec<TrafficUsage>, Error> {
+    let stmt = client
+        .prepare(
+            "SELECT day::TEXT, client, server, transfer_rx, transfer_tx \
+             FROM traffic \
+             WHERE day >= COALESCE($1::TEXT::DATE, current_date - 30) \
+             AND day <= COALESCE($2::TEXT::DATE, current_date) \
+             AND ($3::TEXT IS NULL OR client = $3::TEXT) \
+             ORDER BY day, client, server",
+        )
+        .await?;
+    let rows = client.query(&stmt, &[&from, &to, &name]).await?;
+    Ok(rows
+        .into_iter()
+        .map(|row| TrafficUsage {
+            day: row.get(0),
+            client: row.get(1),
+            server: row.get(2),
+            transfer_rx: row.get::<_, i64>(3) as u64,
+            transfer_tx: row.get::<_, i64>(4) as u64,
+        })
+        .collect())
+}
+
+/// Make sure a day is a valid date in the `YYYY-MM-DD` format.
+pub fn validate_day(day: &str) -> Result<(), Error> {
+    let bytes = day.as_bytes();
+    let format = bytes.len() == 10
+        && bytes.iter().enumerate().all(|(i, b)| match i {
+            4 | 7 => *b == b'-',
+            _ => b.is_ascii_digit(),
+        });
+    if !format || NaiveDate::parse_from_str(day, "%Y-%m-%d").is_err() {
+        bail!("Invalid day {:?}, the format is YYYY-MM-DD", day);
+    }
+    Ok(())
+}
+
+/// Stage the new public key of a server, starting its key rotation.
+pub async fn set_server_pending_key(
+    client: &tokio_postgres::Client,
+    server: &str,
+    public_key: &str,
+) -> Result<(), Error> {
+    let stmt = client
+        .prepare("UPDATE servers SET pending_public_key = $2 WHERE name = $1")
+        .await?;
+    client.execute(&stmt, &[&server, &public_key]).await?;
+    Ok(())
+}
+
+/// Retrieve all the acknowledgements of the pending keys of the servers.
+pub async fn get_server_key_acks(
+    client: &tokio_postgres::Client,
+) -> Result<Vec<ServerKeyAck>, Error> {
+    let stmt = client
+        .prepare(
+            "SELECT server, acked_by, public_key FROM server_key_acks ORDER BY server, acked_by",
+        )
+        .await?;
+    let rows = client.query(&stmt, &[]).await?;
+    Ok(rows
+        .into_iter()
+        .map(|row| ServerKeyAck {
+            server: row.get(0),
+            acked_by: row.get(1),
+            public_key: row.get(2),
+        })
+        .collect())
+}
+
+/// Get the extra DNS records of the network.
+pub async fn get_dns_records(client: &tokio_postgres::Client) -> Result<Vec<DnsRecord>, Error> {
+    let stmt = client
+        .prepare("SELECT name, record_type, value FROM dns_records ORDER BY name, record_type, id")
+        .await?;
+    let rows = client.query(&stmt, &[]).await?;
+    Ok(rows
+        .into_iter()
+        .map(|row| DnsRecord {
+            name: row.get(0),
+            record_type: row.get(1),
+            value: row.get(2),
+        })
+        .collect())
+}
+
+/// Acknowledge the pending key of a server.
+pub async fn ack_server_key(
+    client: &tokio_postgres::Client,
+    server: &str,
+    acked_by: &str,
+    public_key: &str,
+) -> Result<(), Error> {
+    let stmt = client
+        .prepare(
+            "INSERT INTO server_key_acks (server, acked_by, public_key) VALUES ($1, $2, $3) \
+             ON CONFLICT (server, acked_by) DO UPDATE SET public_key = EXCLUDED.public_key",
+        )
+        .await?;
+    client
+        .execute(&stmt, &[&server, &acked_by, &public_key])
+        .await?;
+    Ok(())
+}
+
+/// Complete the key rotation of a server, replacing its public key with the pending one and
+/// flagging the configurations of its clients as outdated.
+pub async fn complete_server_key_rotation(
+    client: &tokio_postgres::Client,
+    server: &str,
+) -> Result<(), Error> {
+    client
+        .execute(
+            &client
+                .prepare(
+                    "UPDATE servers SET public_key = pending_public_key, pending_public_key = NULL \
+                     WHERE name = $1 AND pending_public_key IS NOT NULL",
+                )
+                .await?,
+            &[&server],
+        )
+        .await?;
+    client
+        .execute(
+            &client
+                .prepare("UPDATE connections SET config_outdated = TRUE WHERE server = $1")
+                .await?,
+            &[&server],
+        )
+        .await?;
+    client
+        .execute(
+            &client
+                .prepare("DELETE FROM server_key_acks WHERE server = $1")
+                .await?,
+            &[&server],
+        )
+        .await?;
+    Ok(())
+}
+
+/// Retrieve the connections whose client needs a new configuration, as (client, server) pairs.
+pub async fn get_outdated_configs(
+    client: &tokio_postgres::Client,
+) -> Result<Vec<(String, String)>, Error> {
+    let stmt = client
+        .prepare(
+            "SELECT client, server FROM connections WHERE config_outdated ORDER BY client, server",
+        )
+        .await?;
+    let rows = client.query(&stmt, &[]).await?;
+    Ok(rows
+        .into_iter()
+        .map(|row| (row.get(0), row.get(1)))
+        .collect())
+}
+
+/// Mark the configuration of a client as up to date, once it has been installed on the client.
+/// Returns the number of connections that were outdated.
+pub async fn mark_config_updated(
+    client: &tokio_postgres::Client,
+    name: &str,
+) -> Result<u64, Error> {
+    let stmt = client
+        .prepare(
+            "UPDATE connections SET config_outdated = FALSE \
+             WHERE client = $1 AND config_outdated",
+        )
+        .await?;
+    Ok(client.execute(&stmt, &[&name]).await?)
+}
+
+/// Complete the key rotation of a client, replacing its public key with the pending one.
+pub async fn promote_pending_key(client: &tokio_postgres::Client, name: &str) -> Result<(), Error> {
+    let stmt = client
+        .prepare(
+            "UPDATE clients SET public_key = pending_public_key, pending_public_key = NULL \
+             WHERE name = $1 AND pending_public_key IS NOT NULL",
+        )
+        .await?;
+    client.execute(&stmt, &[&name]).await?;
+    Ok(())
+}
+
+/// Update the server routing the addresses of each client, choosing the server with the lowest
+/// priority value among the ones that see the client online, according to the published status of
+/// the peers. The clients not online anywhere keep their previous server, and only the enabled and
+/// valid connections are considered.
+pub async fn update_active_servers(client: &tokio_postgres::Client) -> Result<(), Error> {
+    // first find the changes, updating the table even without changing any row would notify all
+    // the servers.
+    let stmt = client
+        .prepare(&format!(
+            "SELECT name, active.server \
+             FROM clients \
+             JOIN (SELECT DISTINCT ON (connections.client) connections.client, connections.server \
+                   FROM connections \
+                   JOIN clients ON clients.name = connections.client \
+                   JOIN peer_status ON peer_status.server = connections.server \
+                                   AND peer_status.public_key = clients.public_key \
+                   WHERE peer_status.latest_handshake > extract(epoch from now())::BIGINT - {} \
+                     AND clients.enabled AND connections.enabled AND {} \
+                   ORDER BY connections.client, connections.priority, connections.server) active \
+             ON active.client = clients.name \
+             WHERE clients.active_server IS DISTINCT FROM active.server",
+            ONLINE_TIMEOUT, VALID_CONNECTIONS
+        ))
+        .await?;
+    let rows = client.query(&stmt, &[]).await?;
+    if rows.is_empty() {
+        return Ok(());
+    }
+    let stmt = client
+        .prepare("UPDATE clients SET active_server = $2 WHERE name = $1")
+        .await?;
+    for row in rows {
+        let name: String = row.get(0);
+        let server: String = row.get(1);
+        info!("Client {} is now routed through {}", name, server);
+        client.execute(&stmt, &[&name, &server]).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer_status(latest_handshake: Option<u64>) -> PeerStatus {
+        PeerStatus {
+            public_key: "a2V5".to_string(),
+            endpoint: None,
+            latest_handshake,
+            transfer_rx: 0,
+            transfer_tx: 0,
+        }
+    }
+
+    #[test]
+    fn test_peer_status_is_online() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        assert!(peer_status(Some(now)).is_online());
+        assert!(peer_status(Some(now - ONLINE_TIMEOUT + 10)).is_online());
+        assert!(!peer_status(Some(now - ONLINE_TIMEOUT - 10)).is_online());
+        assert!(!peer_status(None).is_online());
+    }
+
+    #[test]
+    fn test_dns_label() {
+        for name in &["a", "client1", "Alice", "srv-1", "0", &"a".repeat(63)] {
+            assert_eq!(DnsLabel::from_str(name).unwrap().as_str(), *name);
+        }
+        for name in &["", "-a", "a-", "a.b", "a_b", "a b", "à", &"a".repeat(64)] {
+            assert!(DnsLabel::from_str(name).is_err(), "{:?} is valid", name);
+        }
+        assert_eq!(DnsLabel::from_str("srv-1").unwrap().to_string(), "srv-1");
+    }
+
+    #[test]
+    fn test_public_host() {
+        let mut server = Server {
+            name: "alpha".to_string(),
+            subnet_addr: IpAddr::from([10, 0, 1, 0]),
+            subnet_len: 24,
+            address: IpAddr::from([10, 0, 1, 1]),
+            public_address: Some(IpAddr::from([192, 0, 2, 1])),
+            public_port: 51820,
+            public_key: String::new(),
+            hub: false,
+            public_hostname: None,
+            keepalive: None,
+            mtu: None,
+            secondary_subnet_addr: None,
+            secondary_subnet_len: None,
+            secondary_address: None,
+            pending_public_key: None,
+            dns: false,
+        };
+        assert_eq!(server.public_host().as_deref(), Some("192.0.2.1"));
+        server.public_hostname = Some("alpha.example.com".to_string());
+        assert_eq!(server.public_host().as_deref(), Some("alpha.example.com"));
+        server.public_hostname = None;
+        server.public_address = None;
+        assert_eq!(server.public_host(), None);
+    }
+
+    #[test]
+    fn test_validate_day() {
+        assert!(validate_day("2019-10-05").is_ok());
+        assert!(validate_day("2020-02-29").is_ok());
+        assert!(validate_day("2000-02-29").is_ok());
+        assert!(validate_day("2019-02-29").is_err());
+        assert!(validate_day("1900-02-29").is_err());
+        assert!(validate_day("2019-04-31").is_err());
+        assert!(validate_day("2019-13-01").is_err());
+        assert!(validate_day("2019-00-10").is_err());
+        assert!(validate_day("2019-1-10").is_err());
+        assert!(validate_day("2019-01-10'").is_err());
+        assert!(validate_day("yesterday").is_err());
+        assert!(validate_day("").is_err());
+    }
+}
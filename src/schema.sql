@@ -1,25 +1,216 @@
 -- The table where the servers are stored.
 CREATE TABLE IF NOT EXISTS servers (
-  name TEXT PRIMARY KEY,
+  name TEXT PRIMARY KEY CHECK (name ~ '^[A-Za-z0-9]([A-Za-z0-9-]{0,61}[A-Za-z0-9])?$'),
   subnet cidr NOT NULL,
   address inet NOT NULL CHECK(address << subnet),
-  public_address inet NOT NULL,
+  -- The optional subnet and address of the other IP family, for dual-stack networks.
+  secondary_subnet cidr CHECK(family(secondary_subnet) != family(subnet)),
+  secondary_address inet CHECK(secondary_address << secondary_subnet),
+  public_address inet,
+  -- The hostname of the server, for the servers without a static public address.
+  public_hostname TEXT,
   public_port INT NOT NULL,
-  public_key TEXT NOT NULL
+  public_key TEXT NOT NULL,
+  -- Whether the server is a hub, linked with all the other servers.
+  hub BOOLEAN NOT NULL DEFAULT FALSE,
+  -- The keepalive of the peers of the server, overriding the one in its configuration file.
+  keepalive INT CHECK (keepalive BETWEEN 0 AND 65535),
+  -- The MTU of the wireguard interface of the server.
+  mtu INT CHECK (mtu BETWEEN 576 AND 65535),
+  -- The new public key of the server during a key rotation, staged by the server itself.
+  pending_public_key TEXT,
+  -- Whether the server answers the DNS queries of the clients, set by hand. The clients use it for
+  -- all the names (only for the zone with client_dns_zone_only), unless client_dns_servers is set in
+  -- the configuration file.
+  dns BOOLEAN NOT NULL DEFAULT FALSE,
+  CONSTRAINT servers_endpoint_check CHECK (public_address IS NOT NULL OR public_hostname IS NOT NULL),
+  CONSTRAINT servers_secondary_check CHECK ((secondary_subnet IS NULL) = (secondary_address IS NULL))
+);
+-- The table where the explicit links between the servers are stored. If there are no links and no
+-- hubs the servers are connected in full mesh. Each link is stored only once.
+CREATE TABLE IF NOT EXISTS server_links (
+  server_a TEXT REFERENCES servers(name) ON DELETE CASCADE,
+  server_b TEXT REFERENCES servers(name) ON DELETE CASCADE,
+  CHECK (server_a < server_b),
+  PRIMARY KEY (server_a, server_b)
+);
+-- The table where the servers acknowledge the pending keys of the other servers, once they accept
+-- them.
+CREATE TABLE IF NOT EXISTS server_key_acks (
+  server TEXT REFERENCES servers(name) ON DELETE CASCADE,
+  acked_by TEXT REFERENCES servers(name) ON DELETE CASCADE,
+  public_key TEXT NOT NULL,
+  PRIMARY KEY (server, acked_by)
 );
 -- The table where the clients are stored.
 CREATE TABLE IF NOT EXISTS clients (
-  name TEXT PRIMARY KEY,
-  public_key TEXT NOT NULL
+  name TEXT PRIMARY KEY CHECK (name ~ '^[A-Za-z0-9]([A-Za-z0-9-]{0,61}[A-Za-z0-9])?$'),
+  public_key TEXT NOT NULL,
+  -- The server currently routing all the addresses of the client, it's the server with the lowest
+  -- priority value that sees the client online. It's kept updated by the servers.
+  active_server TEXT REFERENCES servers(name) ON DELETE SET NULL,
+  -- The new public key of the client during a key rotation. It's accepted alongside the old one
+  -- and replaces it as soon as a server sees an handshake with it.
+  pending_public_key TEXT,
+  -- The keepalive of the peers in the client configuration, for example for the clients behind NAT.
+  keepalive INT CHECK (keepalive BETWEEN 0 AND 65535),
+  -- The MTU of the wireguard interface of the client.
+  mtu INT CHECK (mtu BETWEEN 576 AND 65535),
+  -- The optional time range in which the client is allowed to connect.
+  valid_from TIMESTAMPTZ,
+  valid_until TIMESTAMPTZ CHECK (valid_until > valid_from),
+  -- Whether the client is allowed to connect. When it's disabled the reason can be specified,
+  -- the time is set automatically.
+  enabled BOOLEAN NOT NULL DEFAULT TRUE,
+  disabled_reason TEXT,
+  disabled_at TIMESTAMPTZ,
+  -- Whether the DNS settings are included in the client configuration.
+  push_dns BOOLEAN NOT NULL DEFAULT TRUE
 );
 -- The table where the relation between the clients and the servers is stored.
--- A client can connect only to the servers listed here.
+-- A client can connect only to the servers listed here, with a different address for each server.
+-- The server with the lowest priority value is the preferred one.
 CREATE TABLE IF NOT EXISTS connections (
   server TEXT REFERENCES servers(name),
-  client TEXT UNIQUE REFERENCES clients(name),
+  client TEXT REFERENCES clients(name),
   address inet NOT NULL UNIQUE,
+  -- The optional address of the other IP family, inside the secondary subnet of the server.
+  secondary_address inet UNIQUE,
+  -- Whether the connection is protected by a preshared key, generated by the server of the
+  -- connection and kept only in its `preshared_keys_file`, never in the database.
+  use_preshared_key BOOLEAN NOT NULL DEFAULT FALSE,
+  priority INT NOT NULL DEFAULT 0,
+  -- Whether all the traffic of the client goes through the server, which acts as an exit node.
+  exit_node BOOLEAN NOT NULL DEFAULT FALSE,
+  -- The optional time range in which the connection is allowed.
+  valid_from TIMESTAMPTZ,
+  valid_until TIMESTAMPTZ CHECK (valid_until > valid_from),
+  -- Whether the connection is allowed, keeping the address and the key while it's disabled.
+  enabled BOOLEAN NOT NULL DEFAULT TRUE,
+  disabled_reason TEXT,
+  disabled_at TIMESTAMPTZ,
+  -- Whether the client needs a new configuration, after the key of the server changed.
+  config_outdated BOOLEAN NOT NULL DEFAULT FALSE,
   PRIMARY KEY (server, client)
 );
+-- The table where the subnets routed through a client are stored, for example the LAN behind a
+-- client router. The subnet is reachable from the entire network through the server of the
+-- connection.
+CREATE TABLE IF NOT EXISTS routed_subnets (
+  subnet cidr PRIMARY KEY,
+  server TEXT NOT NULL,
+  client TEXT NOT NULL,
+  FOREIGN KEY (server, client) REFERENCES connections(server, client) ON DELETE CASCADE
+);
+-- The names are used as DNS labels, so they must be unique ignoring the case.
+CREATE UNIQUE INDEX IF NOT EXISTS servers_name_lower_key ON servers (lower(name));
+CREATE UNIQUE INDEX IF NOT EXISTS clients_name_lower_key ON clients (lower(name));
+-- The table where the extra DNS records of the network are stored: aliases of the servers and of
+-- the clients, addresses of the services behind them, TXT and SRV records. The names and the
+-- targets are relative to the base domain, the value of the SRV records is
+-- `priority weight port target`. The names are dot-separated DNS labels, which can start with an
+-- underscore for the owners of the SRV records, and the TXT records cannot contain control
+-- characters.
+CREATE TABLE IF NOT EXISTS dns_records (
+  id SERIAL PRIMARY KEY,
+  name TEXT NOT NULL CHECK (name ~ '^(_?[A-Za-z0-9]([A-Za-z0-9-]{0,61}[A-Za-z0-9])?)(\.(_?[A-Za-z0-9]([A-Za-z0-9-]{0,61}[A-Za-z0-9])?))*$' AND length(name) <= 253),
+  record_type TEXT NOT NULL CHECK (record_type IN ('CNAME', 'A', 'AAAA', 'TXT', 'SRV')),
+  value TEXT NOT NULL,
+  CHECK (CASE record_type
+           WHEN 'A' THEN family(value::inet) = 4 AND masklen(value::inet) = 32
+           WHEN 'AAAA' THEN family(value::inet) = 6 AND masklen(value::inet) = 128
+           WHEN 'CNAME' THEN value ~ '^(_?[A-Za-z0-9]([A-Za-z0-9-]{0,61}[A-Za-z0-9])?)(\.(_?[A-Za-z0-9]([A-Za-z0-9-]{0,61}[A-Za-z0-9])?))*$' AND value != name
+           WHEN 'SRV' THEN value ~ '^[0-9]+ [0-9]+ [0-9]+ (_?[A-Za-z0-9]([A-Za-z0-9-]{0,61}[A-Za-z0-9])?)(\.(_?[A-Za-z0-9]([A-Za-z0-9-]{0,61}[A-Za-z0-9])?))*$'
+           WHEN 'TXT' THEN value !~ '[[:cntrl:]]'
+           ELSE TRUE
+         END)
+);
+-- The table where each server publishes the live status of its own peers. Changes to this table do
+-- not notify the servers.
+CREATE TABLE IF NOT EXISTS peer_status (
+  server TEXT REFERENCES servers(name) ON DELETE CASCADE,
+  public_key TEXT NOT NULL,
+  endpoint TEXT,
+  latest_handshake BIGINT,
+  transfer_rx BIGINT NOT NULL,
+  transfer_tx BIGINT NOT NULL,
+  updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  PRIMARY KEY (server, public_key)
+);
+-- The table where the traffic of the clients is accounted, day by day, by the server they are
+-- connected to. Changes to this table do not notify the servers.
+CREATE TABLE IF NOT EXISTS traffic (
+  client TEXT REFERENCES clients(name) ON DELETE CASCADE,
+  server TEXT REFERENCES servers(name) ON DELETE CASCADE,
+  day DATE NOT NULL DEFAULT current_date,
+  transfer_rx BIGINT NOT NULL DEFAULT 0,
+  transfer_tx BIGINT NOT NULL DEFAULT 0,
+  PRIMARY KEY (client, server, day)
+);
+
+-- Upgrade the tables created by the older versions.
+-- The names already present are not validated, they can be renamed later.
+ALTER TABLE servers DROP CONSTRAINT IF EXISTS servers_name_check;
+ALTER TABLE servers ADD CONSTRAINT servers_name_check CHECK (name ~ '^[A-Za-z0-9]([A-Za-z0-9-]{0,61}[A-Za-z0-9])?$') NOT VALID;
+ALTER TABLE clients DROP CONSTRAINT IF EXISTS clients_name_check;
+ALTER TABLE clients ADD CONSTRAINT clients_name_check CHECK (name ~ '^[A-Za-z0-9]([A-Za-z0-9-]{0,61}[A-Za-z0-9])?$') NOT VALID;
+ALTER TABLE dns_records DROP CONSTRAINT IF EXISTS dns_records_name_check;
+ALTER TABLE dns_records ADD CONSTRAINT dns_records_name_check
+  CHECK (name ~ '^(_?[A-Za-z0-9]([A-Za-z0-9-]{0,61}[A-Za-z0-9])?)(\.(_?[A-Za-z0-9]([A-Za-z0-9-]{0,61}[A-Za-z0-9])?))*$' AND length(name) <= 253) NOT VALID;
+ALTER TABLE dns_records DROP CONSTRAINT IF EXISTS dns_records_check;
+ALTER TABLE dns_records ADD CONSTRAINT dns_records_check
+  CHECK (CASE record_type
+           WHEN 'A' THEN family(value::inet) = 4 AND masklen(value::inet) = 32
+           WHEN 'AAAA' THEN family(value::inet) = 6 AND masklen(value::inet) = 128
+           WHEN 'CNAME' THEN value ~ '^(_?[A-Za-z0-9]([A-Za-z0-9-]{0,61}[A-Za-z0-9])?)(\.(_?[A-Za-z0-9]([A-Za-z0-9-]{0,61}[A-Za-z0-9])?))*$' AND value != name
+           WHEN 'SRV' THEN value ~ '^[0-9]+ [0-9]+ [0-9]+ (_?[A-Za-z0-9]([A-Za-z0-9-]{0,61}[A-Za-z0-9])?)(\.(_?[A-Za-z0-9]([A-Za-z0-9-]{0,61}[A-Za-z0-9])?))*$'
+           WHEN 'TXT' THEN value !~ '[[:cntrl:]]'
+           ELSE TRUE
+         END) NOT VALID;
+ALTER TABLE servers ADD COLUMN IF NOT EXISTS hub BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE servers ADD COLUMN IF NOT EXISTS public_hostname TEXT;
+ALTER TABLE servers ALTER COLUMN public_address DROP NOT NULL;
+ALTER TABLE servers DROP CONSTRAINT IF EXISTS servers_endpoint_check;
+ALTER TABLE servers ADD CONSTRAINT servers_endpoint_check
+  CHECK (public_address IS NOT NULL OR public_hostname IS NOT NULL);
+ALTER TABLE servers ADD COLUMN IF NOT EXISTS keepalive INT CHECK (keepalive BETWEEN 0 AND 65535);
+ALTER TABLE servers ADD COLUMN IF NOT EXISTS mtu INT CHECK (mtu BETWEEN 576 AND 65535);
+ALTER TABLE servers ADD COLUMN IF NOT EXISTS secondary_subnet cidr
+  CHECK(family(secondary_subnet) != family(subnet));
+ALTER TABLE servers ADD COLUMN IF NOT EXISTS secondary_address inet
+  CHECK(secondary_address << secondary_subnet);
+-- The preshared keys are not stored in the database anymore.
+ALTER TABLE servers DROP COLUMN IF EXISTS link_preshared_keys;
+DROP TABLE IF EXISTS server_preshared_keys;
+ALTER TABLE servers ADD COLUMN IF NOT EXISTS pending_public_key TEXT;
+ALTER TABLE servers ADD COLUMN IF NOT EXISTS dns BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE servers DROP CONSTRAINT IF EXISTS servers_secondary_check;
+ALTER TABLE servers ADD CONSTRAINT servers_secondary_check
+  CHECK ((secondary_subnet IS NULL) = (secondary_address IS NULL));
+ALTER TABLE clients
+  ADD COLUMN IF NOT EXISTS active_server TEXT REFERENCES servers(name) ON DELETE SET NULL;
+ALTER TABLE clients ADD COLUMN IF NOT EXISTS pending_public_key TEXT;
+ALTER TABLE clients ADD COLUMN IF NOT EXISTS keepalive INT CHECK (keepalive BETWEEN 0 AND 65535);
+ALTER TABLE clients ADD COLUMN IF NOT EXISTS mtu INT CHECK (mtu BETWEEN 576 AND 65535);
+ALTER TABLE clients ADD COLUMN IF NOT EXISTS valid_from TIMESTAMPTZ;
+ALTER TABLE clients ADD COLUMN IF NOT EXISTS valid_until TIMESTAMPTZ CHECK (valid_until > valid_from);
+ALTER TABLE clients ADD COLUMN IF NOT EXISTS enabled BOOLEAN NOT NULL DEFAULT TRUE;
+ALTER TABLE clients ADD COLUMN IF NOT EXISTS disabled_reason TEXT;
+ALTER TABLE clients ADD COLUMN IF NOT EXISTS disabled_at TIMESTAMPTZ;
+ALTER TABLE clients ADD COLUMN IF NOT EXISTS push_dns BOOLEAN NOT NULL DEFAULT TRUE;
+ALTER TABLE connections DROP CONSTRAINT IF EXISTS connections_client_key;
+ALTER TABLE connections ADD COLUMN IF NOT EXISTS priority INT NOT NULL DEFAULT 0;
+ALTER TABLE connections ADD COLUMN IF NOT EXISTS exit_node BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE connections ADD COLUMN IF NOT EXISTS secondary_address inet UNIQUE;
+ALTER TABLE connections ADD COLUMN IF NOT EXISTS use_preshared_key BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE connections DROP COLUMN IF EXISTS preshared_key;
+ALTER TABLE connections ADD COLUMN IF NOT EXISTS valid_from TIMESTAMPTZ;
+ALTER TABLE connections
+  ADD COLUMN IF NOT EXISTS valid_until TIMESTAMPTZ CHECK (valid_until > valid_from);
+ALTER TABLE connections ADD COLUMN IF NOT EXISTS enabled BOOLEAN NOT NULL DEFAULT TRUE;
+ALTER TABLE connections ADD COLUMN IF NOT EXISTS disabled_reason TEXT;
+ALTER TABLE connections ADD COLUMN IF NOT EXISTS disabled_at TIMESTAMPTZ;
+ALTER TABLE connections ADD COLUMN IF NOT EXISTS config_outdated BOOLEAN NOT NULL DEFAULT FALSE;
 
 -- Calling this function will publish an event sent to all the servers that are
 -- listening, causing them to reload the changes in the database.
@@ -40,6 +231,20 @@ CREATE TRIGGER notify_servers_changed
   ON servers
   EXECUTE PROCEDURE notify_changes();
 
+-- Send an update to the servers if the `server_links` table changes.
+DROP TRIGGER IF EXISTS notify_server_links_changed ON public.server_links;
+CREATE TRIGGER notify_server_links_changed
+  AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE
+  ON server_links
+  EXECUTE PROCEDURE notify_changes();
+
+-- Send an update to the servers if the `server_key_acks` table changes.
+DROP TRIGGER IF EXISTS notify_server_key_acks_changed ON public.server_key_acks;
+CREATE TRIGGER notify_server_key_acks_changed
+  AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE
+  ON server_key_acks
+  EXECUTE PROCEDURE notify_changes();
+
 -- Send an update to the servers if the `clients` table changes.
 DROP TRIGGER IF EXISTS notify_clients_changed ON public.clients;
 CREATE TRIGGER notify_clients_changed
@@ -54,6 +259,20 @@ CREATE TRIGGER notify_connections_changed
   ON connections
   EXECUTE PROCEDURE notify_changes();
 
+-- Send an update to the servers if the `dns_records` table changes.
+DROP TRIGGER IF EXISTS notify_dns_records_changed ON public.dns_records;
+CREATE TRIGGER notify_dns_records_changed
+  AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE
+  ON dns_records
+  EXECUTE PROCEDURE notify_changes();
+
+-- Send an update to the servers if the `routed_subnets` table changes.
+DROP TRIGGER IF EXISTS notify_routed_subnets_changed ON public.routed_subnets;
+CREATE TRIGGER notify_routed_subnets_changed
+  AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE
+  ON routed_subnets
+  EXECUTE PROCEDURE notify_changes();
+
 -- This function will raise an exception if some integrity constraints are
 -- violated. The constraints checked here are hard/impossible to do with CHECK
 -- clauses.
@@ -70,6 +289,16 @@ CREATE OR REPLACE FUNCTION check_integrity()
         RAISE EXCEPTION 'Connection with address outside the server';
       END IF;
 
+      -- Make sure the secondary client addresses are inside the secondary network.
+      IF (SELECT COUNT(*)
+          FROM connections c
+          JOIN servers s ON c.server = s.name
+          WHERE c.secondary_address IS NOT NULL
+            AND (s.secondary_subnet IS NULL OR NOT (c.secondary_address << s.secondary_subnet))) > 0
+      THEN
+        RAISE EXCEPTION 'Connection with secondary address outside the server';
+      END IF;
+
       -- Make sure that all the server networks are disjoint.
       IF (SELECT COUNT(*)
           FROM servers s1
@@ -79,19 +308,71 @@ CREATE OR REPLACE FUNCTION check_integrity()
         RAISE EXCEPTION 'Overlapping server subnets';
       END IF;
 
+      -- Make sure that all the secondary server networks are disjoint.
+      IF (SELECT COUNT(*)
+          FROM servers s1
+          JOIN servers s2 ON s1.name != s2.name
+          WHERE (s1.secondary_subnet <<= s2.secondary_subnet)
+            OR (s1.secondary_subnet >>= s2.secondary_subnet)) > 0
+      THEN
+        RAISE EXCEPTION 'Overlapping secondary server subnets';
+      END IF;
+
       -- Make sure the intersection of server IPs and client IPs is empty.
       IF (SELECT COUNT(*)
           FROM connections c
           JOIN servers s ON c.server = s.name
-          WHERE c.address = s.address) > 0
+          WHERE c.address = s.address OR c.secondary_address = s.secondary_address) > 0
       THEN
         RAISE EXCEPTION 'Client with server ip address';
       END IF;
 
-      -- Make sure no client has the name of a server.
-      IF (SELECT COUNT(*) FROM clients c JOIN servers s ON c.name = s.name) > 0
+      -- Make sure that all the routed subnets are disjoint.
+      IF (SELECT COUNT(*)
+          FROM routed_subnets r1
+          JOIN routed_subnets r2 ON r1.subnet != r2.subnet
+          WHERE (r1.subnet <<= r2.subnet) OR (r1.subnet >>= r2.subnet)) > 0
+      THEN
+        RAISE EXCEPTION 'Overlapping routed subnets';
+      END IF;
+
+      -- Make sure the routed subnets are disjoint from the server networks.
+      IF (SELECT COUNT(*)
+          FROM routed_subnets r
+          JOIN servers s ON (r.subnet <<= s.subnet) OR (r.subnet >>= s.subnet)
+            OR (r.subnet <<= s.secondary_subnet) OR (r.subnet >>= s.secondary_subnet)) > 0
       THEN
-          RAISE EXCEPTION 'Client with server name';
+        RAISE EXCEPTION 'Routed subnet overlapping a server subnet';
+      END IF;
+
+      -- Make sure the names of the servers and of the clients are unique, ignoring the case.
+      IF (SELECT COUNT(*) FROM clients c JOIN servers s ON lower(c.name) = lower(s.name)) > 0
+      THEN
+          RAISE EXCEPTION 'Name used by both a server and a client';
+      END IF;
+
+      -- Make sure the names with an alias do not have any other record.
+      IF (SELECT COUNT(*)
+          FROM dns_records r
+          WHERE r.record_type = 'CNAME'
+            AND (lower(r.name) IN (SELECT lower(name) FROM servers
+                UNION SELECT lower(name) FROM clients)
+              OR (SELECT COUNT(*) FROM dns_records r2 WHERE lower(r2.name) = lower(r.name)) > 1)) > 0
+      THEN
+        RAISE EXCEPTION 'DNS alias with the name of another record';
+      END IF;
+
+      -- Make sure the aliases and the SRV records point to existing names.
+      IF (SELECT COUNT(*)
+          FROM dns_records r
+          WHERE r.record_type IN ('CNAME', 'SRV')
+            AND lower(split_part(r.value, ' ', CASE r.record_type WHEN 'SRV' THEN 4 ELSE 1 END))
+              NOT IN (SELECT lower(name) FROM servers
+                UNION SELECT lower(name) FROM clients
+                UNION SELECT lower(name) FROM dns_records
+                  WHERE record_type IN ('CNAME', 'A', 'AAAA'))) > 0
+      THEN
+        RAISE EXCEPTION 'DNS record pointing to an unknown name';
       END IF;
 
       RETURN NULL;
@@ -99,17 +380,17 @@ CREATE OR REPLACE FUNCTION check_integrity()
   $$
   LANGUAGE PLPGSQL;
 
--- Check the constraints on `servers` changes.
+-- Check the constraints on `servers` changes, the deletions may leave dangling DNS records.
 DROP TRIGGER IF EXISTS check_integrity_servers ON public.servers;
 CREATE TRIGGER check_integrity_servers
-  AFTER INSERT OR UPDATE
+  AFTER INSERT OR UPDATE OR DELETE
   ON servers
   EXECUTE PROCEDURE check_integrity();
 
--- Check the constraints on `clients` changes.
+-- Check the constraints on `clients` changes, the deletions may leave dangling DNS records.
 DROP TRIGGER IF EXISTS check_integrity_clients ON public.clients;
 CREATE TRIGGER check_integrity_clients
-  AFTER INSERT OR UPDATE
+  AFTER INSERT OR UPDATE OR DELETE
   ON clients
   EXECUTE PROCEDURE check_integrity();
 
@@ -119,3 +400,50 @@ CREATE TRIGGER check_integrity_connections
   AFTER INSERT OR UPDATE
   ON connections
   EXECUTE PROCEDURE check_integrity();
+
+-- Check the constraints on `routed_subnets` changes.
+DROP TRIGGER IF EXISTS check_integrity_routed_subnets ON public.routed_subnets;
+CREATE TRIGGER check_integrity_routed_subnets
+  AFTER INSERT OR UPDATE
+  ON routed_subnets
+  EXECUTE PROCEDURE check_integrity();
+
+-- Check the constraints on `dns_records` changes.
+DROP TRIGGER IF EXISTS check_integrity_dns_records ON public.dns_records;
+CREATE TRIGGER check_integrity_dns_records
+  AFTER INSERT OR UPDATE OR DELETE
+  ON dns_records
+  EXECUTE PROCEDURE check_integrity();
+
+-- Keep track of when the clients and the connections are disabled, forgetting the reason when they
+-- are enabled again.
+CREATE OR REPLACE FUNCTION set_disabled_at()
+  RETURNS trigger
+  AS $$
+    BEGIN
+      IF NEW.enabled THEN
+        NEW.disabled_reason := NULL;
+        NEW.disabled_at := NULL;
+      ELSIF TG_OP = 'INSERT' OR OLD.enabled THEN
+        NEW.disabled_at := now();
+      END IF;
+      RETURN NEW;
+    END
+  $$
+  LANGUAGE PLPGSQL;
+
+-- Set the time the client has been disabled.
+DROP TRIGGER IF EXISTS set_disabled_at_clients ON public.clients;
+CREATE TRIGGER set_disabled_at_clients
+  BEFORE INSERT OR UPDATE
+  ON clients
+  FOR EACH ROW
+  EXECUTE PROCEDURE set_disabled_at();
+
+-- Set the time the connection has been disabled.
+DROP TRIGGER IF EXISTS set_disabled_at_connections ON public.connections;
+CREATE TRIGGER set_disabled_at_connections
+  BEFORE INSERT OR UPDATE
+  ON connections
+  FOR EACH ROW
+  EXECUTE PROCEDURE set_disabled_at();
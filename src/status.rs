@@ -0,0 +1,278 @@
+use failure::Error;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use tokio_postgres::Client;
+
+use crate::config::ServerConfig;
+use crate::events::{Event, EventBus, PeerKind};
+use crate::schema;
+use crate::schema::PeerStatus;
+use crate::wireguard;
+
+/// Keeps track of which peers of this server are online.
+#[derive(Debug, Default)]
+pub struct PeerTracker {
+    /// The public keys of the peers that were online at the last check, `None` before the first
+    /// one.
+    online: Mutex<Option<HashSet<String>>>,
+    /// The public keys of the peers that have been online at least once, also before a restart.
+    seen: Mutex<HashSet<String>>,
+}
+
+impl PeerTracker {
+    /// Whether the state of the peers has not been recorded yet.
+    fn is_first_check(&self) -> bool {
+        self.online.lock().unwrap().is_none()
+    }
+
+    /// Record the peers with an handshake in a status published before a restart as already seen,
+    /// so that they are not reported as connected for the first time.
+    fn restore<'a, I: IntoIterator<Item = &'a PeerStatus>>(&self, peers: I) {
+        let mut seen = self.seen.lock().unwrap();
+        seen.extend(
+            peers
+                .into_iter()
+                .filter(|p| p.latest_handshake.is_some())
+                .map(|p| p.public_key.clone()),
+        );
+    }
+
+    /// Update the set of online peers, returning the public keys of the peers that changed state,
+    /// with their new state and whether it's the first time they are online. The first time only
+    /// the initial state is recorded.
+    fn update(&self, peers: &[PeerStatus]) -> Vec<(String, bool, bool)> {
+        let current: HashSet<_> = peers
+            .iter()
+            .filter(|p| p.is_online())
+            .map(|p| p.public_key.clone())
+            .collect();
+        let mut online = self.online.lock().unwrap();
+        let mut seen = self.seen.lock().unwrap();
+        let mut changes = Vec::new();
+        if let Some(previous) = online.as_ref() {
+            for peer in peers {
+                let was_online = previous.contains(&peer.public_key);
+                let is_online = current.contains(&peer.public_key);
+                if was_online != is_online {
+                    let first = is_online && !seen.contains(&peer.public_key);
+                    changes.push((peer.public_key.clone(), is_online, first));
+                }
+            }
+        }
+        seen.extend(current.iter().cloned());
+        *online = Some(current);
+        changes
+    }
+}
+
+/// Keeps track of the clients allowed to connect to this server.
+#[derive(Debug, Default)]
+pub struct ClientTracker {
+    /// The names of the clients at the last check, `None` before the first one.
+    clients: Mutex<Option<HashSet<String>>>,
+}
+
+impl ClientTracker {
+    /// Publish an event for each client added or removed from this server since the last check.
+    /// The first time only the initial state is recorded.
+    pub async fn check(
+        &self,
+        config: &ServerConfig,
+        client: &Client,
+        events: &EventBus,
+    ) -> Result<(), Error> {
+        let current: HashSet<_> = schema::get_clients(client, Some(&config.name))
+            .await?
+            .into_iter()
+            .map(|c| c.client.name)
+            .collect();
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(previous) = clients.as_ref() {
+            for name in current.difference(previous) {
+                events.publish(Event::ClientAdded { name: name.clone() });
+            }
+            for name in previous.difference(&current) {
+                events.publish(Event::ClientRemoved { name: name.clone() });
+            }
+        }
+        *clients = Some(current);
+        Ok(())
+    }
+}
+
+/// Publish the live status of the peers of this server in the database, making it available to all
+/// the other servers of the network, and publish an event for the peers that went online or
+/// offline. The servers routing the clients connected to more servers are updated accordingly.
+pub async fn publish_status(
+    config: &ServerConfig,
+    client: &Client,
+    tracker: &PeerTracker,
+    events: &EventBus,
+) -> Result<(), Error> {
+    let peers = wireguard::get_peers_status(config).await?;
+    if tracker.is_first_check() {
+        // the status published before the restart is replaced right after
+        let published = schema::get_peer_status(client).await?;
+        tracker.restore(
+            published
+                .iter()
+                .filter(|p| p.server == config.name)
+                .map(|p| &p.status),
+        );
+    }
+    schema::publish_peer_status(client, &config.name, &peers).await?;
+    debug!("Published the status of {} peers", peers.len());
+    schema::update_active_servers(client).await?;
+    promote_pending_keys(config, client, &peers).await?;
+
+    let changes = tracker.update(&peers);
+    if changes.is_empty() {
+        return Ok(());
+    }
+    let names = get_peer_names(config, client).await?;
+    for (public_key, online, first_connection) in changes {
+        let (name, kind) = match names.get(&public_key) {
+            Some((name, kind)) => (Some(name.clone()), *kind),
+            // the peer has been removed from the database in the meantime
+            None => (None, PeerKind::Client),
+        };
+        if online {
+            events.publish(Event::PeerOnline {
+                name,
+                public_key,
+                kind,
+                first_connection,
+            });
+        } else {
+            events.publish(Event::PeerOffline {
+                name,
+                public_key,
+                kind,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Complete the key rotation of the clients that completed an handshake with their pending key.
+async fn promote_pending_keys(
+    config: &ServerConfig,
+    client: &Client,
+    peers: &[PeerStatus],
+) -> Result<(), Error> {
+    for conn in schema::get_clients(client, Some(&config.name)).await? {
+        let pending = match &conn.client.pending_public_key {
+            Some(pending) => pending,
+            None => continue,
+        };
+        if peers
+            .iter()
+            .any(|p| p.public_key == *pending && p.latest_handshake.is_some())
+        {
+            info!("Client {} is now using its new key", conn.client.name);
+            schema::promote_pending_key(client, &conn.client.name).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Build the map from the public keys of the peers of this server to their name and kind.
+async fn get_peer_names(
+    config: &ServerConfig,
+    client: &Client,
+) -> Result<HashMap<String, (String, PeerKind)>, Error> {
+    let mut names = HashMap::new();
+    for server in schema::get_servers(client).await? {
+        names.insert(server.public_key, (server.name, PeerKind::Server));
+    }
+    for conn in schema::get_clients(client, Some(&config.name)).await? {
+        if let Some(pending_public_key) = conn.client.pending_public_key {
+            names.insert(
+                pending_public_key,
+                (conn.client.name.clone(), PeerKind::Client),
+            );
+        }
+        names.insert(conn.client.public_key, (conn.client.name, PeerKind::Client));
+    }
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn peer(public_key: &str, online: bool) -> PeerStatus {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        PeerStatus {
+            public_key: public_key.to_string(),
+            endpoint: None,
+            latest_handshake: if online { Some(now) } else { Some(now - 3600) },
+            transfer_rx: 0,
+            transfer_tx: 0,
+        }
+    }
+
+    #[test]
+    fn test_peer_tracker_initial_state() {
+        let tracker = PeerTracker::default();
+        assert!(tracker
+            .update(&[peer("a", true), peer("b", false)])
+            .is_empty());
+    }
+
+    #[test]
+    fn test_peer_tracker_changes() {
+        let tracker = PeerTracker::default();
+        tracker.update(&[peer("a", true), peer("b", false)]);
+        let changes = tracker.update(&[peer("a", false), peer("b", true), peer("c", false)]);
+        assert_eq!(
+            changes,
+            vec![
+                ("a".to_string(), false, false),
+                ("b".to_string(), true, true)
+            ]
+        );
+        assert!(tracker
+            .update(&[peer("a", false), peer("b", true)])
+            .is_empty());
+    }
+
+    #[test]
+    fn test_peer_tracker_restore() {
+        let tracker = PeerTracker::default();
+        assert!(tracker.is_first_check());
+        let mut never = peer("b", false);
+        never.latest_handshake = None;
+        tracker.restore(&[peer("a", false), never]);
+        tracker.update(&[peer("a", false), peer("b", false)]);
+        assert!(!tracker.is_first_check());
+        let changes = tracker.update(&[peer("a", true), peer("b", true)]);
+        assert_eq!(
+            changes,
+            vec![
+                ("a".to_string(), true, false),
+                ("b".to_string(), true, true)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_peer_tracker_reconnection() {
+        let tracker = PeerTracker::default();
+        tracker.update(&[peer("a", true), peer("b", false)]);
+        tracker.update(&[peer("a", false), peer("b", true)]);
+        tracker.update(&[peer("a", false), peer("b", false)]);
+        let changes = tracker.update(&[peer("a", true), peer("b", true)]);
+        assert_eq!(
+            changes,
+            vec![
+                ("a".to_string(), true, false),
+                ("b".to_string(), true, false)
+            ]
+        );
+    }
+}
@@ -0,0 +1,321 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::net::IpAddr;
+
+use crate::schema::Server;
+
+/// The links between the servers of the network.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Topology {
+    /// The servers directly linked with each server.
+    links: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl Topology {
+    /// Build the topology of the network. If there are no explicit links and no hubs, every server
+    /// is linked with all the others. Otherwise the hubs are linked with all the servers and the
+    /// other servers only with the ones they have an explicit link with.
+    pub fn new(servers: &[Server], explicit_links: &[(String, String)]) -> Topology {
+        let full_mesh = explicit_links.is_empty() && !servers.iter().any(|s| s.hub);
+        let mut links: BTreeMap<_, _> = servers
+            .iter()
+            .map(|s| (s.name.clone(), BTreeSet::new()))
+            .collect();
+        let mut link = |a: &str, b: &str| {
+            if a == b || !links.contains_key(a) || !links.contains_key(b) {
+                return;
+            }
+            links.get_mut(a).unwrap().insert(b.to_string());
+            links.get_mut(b).unwrap().insert(a.to_string());
+        };
+        for s1 in servers {
+            for s2 in servers {
+                if full_mesh || s1.hub || s2.hub {
+                    link(&s1.name, &s2.name);
+                }
+            }
+        }
+        for (a, b) in explicit_links {
+            link(a, b);
+        }
+        Topology { links }
+    }
+
+    /// The names of the servers directly linked with the specified one.
+    pub fn neighbors(&self, name: &str) -> Vec<&str> {
+        self.links
+            .get(name)
+            .map(|l| l.iter().map(|s| s.as_str()).collect())
+            .unwrap_or_default()
+    }
+
+    /// Compute the next hop for reaching every other server from the specified one, following the
+    /// shortest path. The unreachable servers are not present.
+    ///
+    /// Wireguard drops the packets coming from a peer whose source is not routed through that peer,
+    /// so the traffic between two servers must follow the same path in both directions. The ties
+    /// between the paths with the same length are broken numbering the links in the same way on
+    /// every server and preferring the path whose links have the lowest sum of `2^number`: that
+    /// path is unique and the same from both ends, and so are all its sub-paths.
+    pub fn next_hops(&self, from: &str) -> HashMap<String, String> {
+        let mut numbers = HashMap::new();
+        for (a, neighbors) in &self.links {
+            for b in neighbors.iter().filter(|b| a < *b) {
+                let number = numbers.len();
+                numbers.insert((a.as_str(), b.as_str()), number);
+                numbers.insert((b.as_str(), a.as_str()), number);
+            }
+        }
+        // the best path to each server found so far, as the numbers of its links in decreasing
+        // order, with its first hop. With the same length, comparing them compares their sums.
+        let mut best: HashMap<&str, (Vec<usize>, &str)> = HashMap::new();
+        best.insert(from, (vec![], from));
+        let mut layer = vec![from];
+        while !layer.is_empty() {
+            let mut next: BTreeMap<&str, (Vec<usize>, &str)> = BTreeMap::new();
+            for server in &layer {
+                let (path, hop) = best[server].clone();
+                for neighbor in self.neighbors(server) {
+                    if best.contains_key(neighbor) {
+                        continue;
+                    }
+                    let mut path = path.clone();
+                    path.push(numbers[&(*server, neighbor)]);
+                    path.sort_unstable_by(|a, b| b.cmp(a));
+                    let hop = if *server == from { neighbor } else { hop };
+                    match next.get(neighbor) {
+                        Some((other, _)) if *other <= path => {}
+                        _ => {
+                            next.insert(neighbor, (path, hop));
+                        }
+                    }
+                }
+            }
+            layer = next.keys().cloned().collect();
+            best.extend(next);
+        }
+        best.into_iter()
+            .filter(|(server, _)| *server != from)
+            .map(|(server, (_, hop))| (server.to_string(), hop.to_string()))
+            .collect()
+    }
+}
+
+/// Whether the network `outer` contains the network `inner`.
+pub fn network_contains(outer: (IpAddr, u8), inner: (IpAddr, u8)) -> bool {
+    if outer.1 > inner.1 {
+        return false;
+    }
+    match (outer.0, inner.0) {
+        (IpAddr::V4(a), IpAddr::V4(b)) => {
+            let mask = u32::max_value()
+                .checked_shl(32 - outer.1 as u32)
+                .unwrap_or(0);
+            u32::from(a) & mask == u32::from(b) & mask
+        }
+        (IpAddr::V6(a), IpAddr::V6(b)) => {
+            let mask = u128::max_value()
+                .checked_shl(128 - outer.1 as u32)
+                .unwrap_or(0);
+            u128::from(a) & mask == u128::from(b) & mask
+        }
+        _ => false,
+    }
+}
+
+/// Aggregate a list of networks, removing the duplicates and the ones already contained in another
+/// network of the list.
+pub fn aggregate(networks: &[(IpAddr, u8)]) -> Vec<(IpAddr, u8)> {
+    let mut result: Vec<(IpAddr, u8)> = Vec::new();
+    for (index, network) in networks.iter().enumerate() {
+        let covered = networks.iter().enumerate().any(|(other_index, other)| {
+            network_contains(*other, *network) && (other != network || other_index < index)
+        });
+        if !covered {
+            result.push(*network);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server(name: &str, hub: bool) -> Server {
+        Server {
+            name: name.to_string(),
+            subnet_addr: IpAddr::from([10, 0, 0, 0]),
+            subnet_len: 24,
+            address: IpAddr::from([10, 0, 0, 1]),
+            public_address: None,
+            public_port: 51820,
+            public_key: String::new(),
+            hub,
+            public_hostname: None,
+            keepalive: None,
+            mtu: None,
+            secondary_subnet_addr: None,
+            secondary_subnet_len: None,
+            secondary_address: None,
+            pending_public_key: None,
+            dns: false,
+        }
+    }
+
+    fn links(links: &[(&str, &str)]) -> Vec<(String, String)> {
+        links
+            .iter()
+            .map(|(a, b)| (a.to_string(), b.to_string()))
+            .collect()
+    }
+
+    /// The path from a server to another, following the next hops of each server.
+    fn path(topology: &Topology, from: &str, to: &str) -> Vec<String> {
+        let mut path = vec![from.to_string()];
+        while path.last().unwrap() != to {
+            let hop = topology.next_hops(path.last().unwrap())[to].clone();
+            assert!(!path.contains(&hop), "loop in {:?}", path);
+            path.push(hop);
+        }
+        path
+    }
+
+    /// Check that the traffic between every pair of servers follows the same path in both
+    /// directions, and that each server on the path accepts the sources from the previous one.
+    fn assert_symmetric(topology: &Topology) {
+        let names: Vec<_> = topology.links.keys().cloned().collect();
+        for a in &names {
+            for b in names.iter().filter(|b| *b != a) {
+                let forward = path(topology, a, b);
+                let mut backward = path(topology, b, a);
+                backward.reverse();
+                assert_eq!(forward, backward);
+                for pair in forward.windows(2) {
+                    assert_eq!(topology.next_hops(&pair[1])[a], pair[0]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_full_mesh() {
+        let servers = vec![server("a", false), server("b", false), server("c", false)];
+        let topology = Topology::new(&servers, &[]);
+        assert_eq!(topology.neighbors("a"), vec!["b", "c"]);
+        let next_hops = topology.next_hops("a");
+        assert_eq!(next_hops.len(), 2);
+        assert_eq!(next_hops["b"], "b");
+        assert_eq!(next_hops["c"], "c");
+    }
+
+    #[test]
+    fn test_hub_and_spoke() {
+        let servers = vec![
+            server("hub", true),
+            server("s1", false),
+            server("s2", false),
+        ];
+        let topology = Topology::new(&servers, &[]);
+        assert_eq!(topology.neighbors("s1"), vec!["hub"]);
+        assert_eq!(topology.next_hops("s1")["s2"], "hub");
+        assert_symmetric(&topology);
+    }
+
+    #[test]
+    fn test_multiple_hubs() {
+        let servers = vec![
+            server("h1", true),
+            server("h2", true),
+            server("h3", true),
+            server("s1", false),
+            server("s2", false),
+            server("s3", false),
+        ];
+        let topology = Topology::new(&servers, &links(&[("s1", "s2")]));
+        assert_eq!(topology.next_hops("s1")["s2"], "s2");
+        // s1 and s3 are reached through the same hub from both ends
+        let hop = topology.next_hops("s1")["s3"].clone();
+        assert_eq!(topology.next_hops("s3")["s1"], hop);
+        assert_symmetric(&topology);
+    }
+
+    #[test]
+    fn test_crossed_paths() {
+        // a greedy choice of the first hop by name would go a -> b1 -> d2 -> c, and back
+        // c -> d1 -> b2 -> a
+        let servers: Vec<_> = ["a", "b1", "b2", "c", "d1", "d2"]
+            .iter()
+            .map(|n| server(n, false))
+            .collect();
+        let topology = Topology::new(
+            &servers,
+            &links(&[
+                ("a", "b1"),
+                ("a", "b2"),
+                ("b1", "d2"),
+                ("b2", "d1"),
+                ("c", "d1"),
+                ("c", "d2"),
+            ]),
+        );
+        assert_symmetric(&topology);
+    }
+
+    #[test]
+    fn test_unreachable() {
+        let servers = vec![server("a", false), server("b", false), server("c", false)];
+        let topology = Topology::new(&servers, &links(&[("a", "b"), ("a", "unknown")]));
+        assert_eq!(topology.neighbors("c"), Vec::<&str>::new());
+        assert_eq!(topology.next_hops("a").len(), 1);
+        assert!(topology.next_hops("c").is_empty());
+    }
+
+    #[test]
+    fn test_network_contains() {
+        let net = |a: [u8; 4], len| (IpAddr::from(a), len);
+        assert!(network_contains(
+            net([10, 0, 0, 0], 16),
+            net([10, 0, 5, 0], 24)
+        ));
+        assert!(network_contains(
+            net([10, 0, 0, 0], 16),
+            net([10, 0, 0, 0], 16)
+        ));
+        assert!(!network_contains(
+            net([10, 0, 5, 0], 24),
+            net([10, 0, 0, 0], 16)
+        ));
+        assert!(!network_contains(
+            net([10, 1, 0, 0], 16),
+            net([10, 0, 5, 0], 24)
+        ));
+        assert!(network_contains(
+            net([0, 0, 0, 0], 0),
+            net([192, 168, 1, 1], 32)
+        ));
+        let v6: IpAddr = "fd12::".parse().unwrap();
+        let host: IpAddr = "fd12::1:2".parse().unwrap();
+        assert!(network_contains((v6, 48), (host, 128)));
+        assert!(!network_contains((v6, 48), net([10, 0, 0, 0], 8)));
+    }
+
+    #[test]
+    fn test_aggregate() {
+        let net = |a: [u8; 4], len| (IpAddr::from(a), len);
+        assert_eq!(
+            aggregate(&[
+                net([10, 0, 1, 2], 32),
+                net([10, 0, 1, 0], 24),
+                net([10, 0, 2, 0], 24),
+                net([10, 0, 1, 0], 24),
+                net([10, 0, 2, 3], 32),
+                net([10, 0, 3, 3], 32),
+            ]),
+            vec![
+                net([10, 0, 1, 0], 24),
+                net([10, 0, 2, 0], 24),
+                net([10, 0, 3, 3], 32)
+            ]
+        );
+    }
+}
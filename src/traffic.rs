@@ -0,0 +1,192 @@
+use failure::Error;
+use futures::lock::Mutex;
+use std::collections::HashMap;
+use std::future::Future;
+use tokio_postgres::Client;
+
+use crate::config::ServerConfig;
+use crate::schema;
+use crate::schema::PeerStatus;
+use crate::wireguard;
+
+/// Accounting of the traffic of the clients connected to this server.
+///
+/// The transfer counters of the device are sampled periodically and the difference with the
+/// previous sample is stored in the database. The counters are reset by wireguard when the peers
+/// are re-added by `wg setconf`, so the configuration is applied with `around_reset`, which samples
+/// the device right before and accounts all the counters right after. The samples are serialized,
+/// so a periodic sample never interleaves with an update of the configuration.
+#[derive(Debug, Default)]
+pub struct TrafficAccounting {
+    /// The last sampled counters of each peer, by public key, as (rx, tx).
+    last: Mutex<HashMap<String, (u64, u64)>>,
+}
+
+impl TrafficAccounting {
+    /// Sample the transfer counters of the device and account the traffic since the last sample.
+    pub async fn sample(&self, config: &ServerConfig, client: &Client) -> Result<(), Error> {
+        let mut last = self.last.lock().await;
+        let peers = wireguard::get_peers_status(config).await?;
+        let deltas = update(&mut last, &peers, false);
+        account(config, client, deltas).await
+    }
+
+    /// Run an operation that resets the transfer counters of the device, accounting the traffic
+    /// right before it and all the traffic after it. If the operation fails the counters may not
+    /// have been reset, and the device is sampled as usual.
+    pub async fn around_reset<F, T>(
+        &self,
+        config: &ServerConfig,
+        client: &Client,
+        operation: F,
+    ) -> Result<T, Error>
+    where
+        F: Future<Output = Result<T, Error>>,
+    {
+        let mut last = self.last.lock().await;
+        match wireguard::get_peers_status(config).await {
+            Ok(peers) => {
+                let deltas = update(&mut last, &peers, false);
+                if let Err(e) = account(config, client, deltas).await {
+                    warn!("Failed to account the traffic of the peers: {:?}", e);
+                }
+            }
+            Err(e) => warn!("Failed to account the traffic of the peers: {:?}", e),
+        }
+        let result = operation.await;
+        match wireguard::get_peers_status(config).await {
+            Ok(peers) => {
+                let deltas = update(&mut last, &peers, result.is_ok());
+                if let Err(e) = account(config, client, deltas).await {
+                    warn!("Failed to account the traffic of the peers: {:?}", e);
+                }
+            }
+            Err(e) => {
+                warn!("Failed to account the traffic of the peers: {:?}", e);
+                // without a new baseline the traffic would be counted twice
+                last.clear();
+            }
+        }
+        result
+    }
+}
+
+/// Store the traffic of the clients in the database.
+async fn account(
+    config: &ServerConfig,
+    client: &Client,
+    deltas: Vec<(String, u64, u64)>,
+) -> Result<(), Error> {
+    if deltas.is_empty() {
+        return Ok(());
+    }
+    let clients = schema::get_clients(client, Some(&config.name)).await?;
+    for (public_key, rx, tx) in deltas {
+        // the peer may also be a server, whose traffic is not accounted
+        if let Some(conn) = clients.iter().find(|c| c.client.public_key == public_key) {
+            schema::add_traffic(client, &config.name, &conn.client.name, rx, tx).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Store the new value of the counters, returning the traffic of the peers since the last sample.
+/// If `reset` is true the counters have been reset after the last sample, and they contain only new
+/// traffic.
+fn update(
+    last: &mut HashMap<String, (u64, u64)>,
+    peers: &[PeerStatus],
+    reset: bool,
+) -> Vec<(String, u64, u64)> {
+    let mut deltas = Vec::new();
+    let mut current = HashMap::new();
+    for peer in peers {
+        let counters = (peer.transfer_rx, peer.transfer_tx);
+        let prev = if reset {
+            Some((0, 0))
+        } else {
+            // a peer without a previous sample is only used as baseline
+            last.get(&peer.public_key).cloned()
+        };
+        if let Some(prev) = prev {
+            // if a counter went backwards the peer has been reset, and the counter contains only
+            // the traffic after the reset
+            let rx = if counters.0 >= prev.0 {
+                counters.0 - prev.0
+            } else {
+                counters.0
+            };
+            let tx = if counters.1 >= prev.1 {
+                counters.1 - prev.1
+            } else {
+                counters.1
+            };
+            if rx > 0 || tx > 0 {
+                deltas.push((peer.public_key.clone(), rx, tx));
+            }
+        }
+        current.insert(peer.public_key.clone(), counters);
+    }
+    *last = current;
+    deltas
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(public_key: &str, transfer_rx: u64, transfer_tx: u64) -> PeerStatus {
+        PeerStatus {
+            public_key: public_key.to_string(),
+            endpoint: None,
+            latest_handshake: None,
+            transfer_rx,
+            transfer_tx,
+        }
+    }
+
+    #[test]
+    fn test_update_first_sample_is_baseline() {
+        let mut last = HashMap::new();
+        assert!(update(&mut last, &[peer("a", 100, 200)], false).is_empty());
+        assert_eq!(last["a"], (100, 200));
+    }
+
+    #[test]
+    fn test_update_delta() {
+        let mut last = HashMap::new();
+        update(&mut last, &[peer("a", 100, 200), peer("b", 5, 5)], false);
+        let deltas = update(&mut last, &[peer("a", 150, 260), peer("b", 5, 5)], false);
+        assert_eq!(deltas, vec![("a".to_string(), 50, 60)]);
+    }
+
+    #[test]
+    fn test_update_counter_went_backwards() {
+        let mut last = HashMap::new();
+        update(&mut last, &[peer("a", 100, 200)], false);
+        let deltas = update(&mut last, &[peer("a", 30, 250)], false);
+        assert_eq!(deltas, vec![("a".to_string(), 30, 50)]);
+    }
+
+    #[test]
+    fn test_update_with_reset() {
+        let mut last = HashMap::new();
+        update(&mut last, &[peer("a", 100, 200)], false);
+        // the counters grew past the previous sample after the reset, all of them is new traffic
+        let deltas = update(&mut last, &[peer("a", 300, 10), peer("b", 7, 8)], true);
+        assert_eq!(
+            deltas,
+            vec![("a".to_string(), 300, 10), ("b".to_string(), 7, 8)]
+        );
+        let deltas = update(&mut last, &[peer("a", 310, 10)], false);
+        assert_eq!(deltas, vec![("a".to_string(), 10, 0)]);
+    }
+
+    #[test]
+    fn test_update_forgets_removed_peers() {
+        let mut last = HashMap::new();
+        update(&mut last, &[peer("a", 100, 200)], false);
+        update(&mut last, &[], false);
+        assert!(update(&mut last, &[peer("a", 150, 250)], false).is_empty());
+    }
+}
@@ -1,13 +1,47 @@
+use crate::client_config::ClientFormat;
 use crate::config::ServerConfig;
+use crate::events::EventBus;
 use crate::schema;
-use crate::wireguard::gen_client_config;
+use crate::schema::{DnsLabel, PeerStatus};
+use crate::topology::Topology;
+use crate::wireguard::{gen_client_config, get_peers_status};
 use failure::Error;
+use futures::StreamExt;
 use hyper::{Body, Request, Response, StatusCode};
+use percent_encoding::percent_decode;
 use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
 use tokio::fs::File;
 use tokio::io::AsyncReadExt;
 use tokio_postgres::Client;
 
+/// Live status of a peer of this server. This will be serialized and exposed in the JSON API.
+#[derive(Debug, Clone, Serialize)]
+struct NetworkStatusPeer {
+    /// Whether the peer completed an handshake recently.
+    pub online: bool,
+    /// The UNIX timestamp of the most recent handshake, if any.
+    pub latest_handshake: Option<u64>,
+    /// The address from which the peer has been seen for the last time.
+    pub endpoint: Option<String>,
+    /// The number of bytes received from the peer.
+    pub transfer_rx: u64,
+    /// The number of bytes sent to the peer.
+    pub transfer_tx: u64,
+}
+
+impl From<&PeerStatus> for NetworkStatusPeer {
+    fn from(peer: &PeerStatus) -> Self {
+        NetworkStatusPeer {
+            online: peer.is_online(),
+            latest_handshake: peer.latest_handshake,
+            endpoint: peer.endpoint.clone(),
+            transfer_rx: peer.transfer_rx,
+            transfer_tx: peer.transfer_tx,
+        }
+    }
+}
+
 /// Status of a server in the network. This will be serialized and exposed in the JSON API.
 #[derive(Debug, Clone, Serialize)]
 struct NetworkStatusServer {
@@ -19,10 +53,18 @@ struct NetworkStatusServer {
     pub subnet_len: u8,
     /// The address of the server inside its subnet.
     pub address: String,
+    /// The subnet of the other IP family the server manages, if any.
+    pub secondary_subnet: Option<String>,
+    /// The length of the secondary subnet of the server.
+    pub secondary_subnet_len: Option<u8>,
+    /// The address of the server inside its secondary subnet.
+    pub secondary_address: Option<String>,
     /// The public address of the server.
     pub endpoint: String,
     /// The public port of the server.
     pub endpoint_port: u16,
+    /// The live status of the server, as seen by this server. `None` for this server itself.
+    pub status: Option<NetworkStatusPeer>,
 }
 
 /// Status of a client in the network. This will be serialized and exposed in the JSON API.
@@ -34,6 +76,18 @@ struct NetworkStatusClient {
     pub server: String,
     /// The private IP address of the client in the server's network.
     pub address: String,
+    /// The private IP address of the other family of the client, if any.
+    pub secondary_address: Option<String>,
+    /// The priority of the connection to the server, lower values are preferred.
+    pub priority: i32,
+    /// Whether the client or the connection has been disabled.
+    pub suspended: bool,
+    /// Why the client or the connection has been disabled, if specified.
+    pub suspended_reason: Option<String>,
+    /// The UNIX timestamp of when the client or the connection has been disabled.
+    pub suspended_at: Option<u64>,
+    /// The live status of the client, only known if it's connected to this server.
+    pub status: Option<NetworkStatusPeer>,
 }
 
 /// The response of the `/data` JSON API.
@@ -45,6 +99,89 @@ struct NetworkStatus {
     pub clients: Vec<NetworkStatusClient>,
     /// The base domain of the DNS.
     pub base_domain: String,
+    /// The name of the server that produced this status, the live status is from its point of
+    /// view.
+    pub local_server: String,
+}
+
+/// Status of the link between two linked servers, as seen by the first one. This will be serialized and
+/// exposed in the JSON API.
+#[derive(Debug, Clone, Serialize)]
+struct NetworkLinkStatus {
+    /// The name of the server that published the status.
+    pub from: String,
+    /// The name of the other end of the link.
+    pub to: String,
+    /// The status of the link, if published.
+    pub status: Option<NetworkStatusPeer>,
+    /// Whether the status has not been updated recently.
+    pub stale: bool,
+}
+
+/// Status of a client, as seen by the server it is connected to. This will be serialized and
+/// exposed in the JSON API.
+#[derive(Debug, Clone, Serialize)]
+struct NetworkClientStatus {
+    /// The name of the client.
+    pub name: String,
+    /// The name of the server.
+    pub server: String,
+    /// The private IP address of the client in the server's network.
+    pub address: String,
+    /// The status of the client, if published.
+    pub status: Option<NetworkStatusPeer>,
+    /// Whether the status has not been updated recently.
+    pub stale: bool,
+}
+
+/// The response of the `/network` JSON API.
+#[derive(Debug, Clone, Serialize)]
+struct NetworkOverview {
+    /// The status of all the direct links between the servers.
+    pub links: Vec<NetworkLinkStatus>,
+    /// The status of all the clients in the network.
+    pub clients: Vec<NetworkClientStatus>,
+    /// The base domain of the DNS.
+    pub base_domain: String,
+}
+
+/// Traffic of a client through a server in a day. This will be serialized and exposed in the JSON
+/// API.
+#[derive(Debug, Clone, Serialize)]
+struct TrafficUsageEntry {
+    /// The day of the traffic, in the `YYYY-MM-DD` format.
+    pub day: String,
+    /// The name of the client.
+    pub client: String,
+    /// The name of the server.
+    pub server: String,
+    /// The number of bytes received from the client.
+    pub transfer_rx: u64,
+    /// The number of bytes sent to the client.
+    pub transfer_tx: u64,
+}
+
+/// Parse the query string of the request, percent-decoding the keys and the values like the forms
+/// do (`+` is a space).
+fn query_params<T>(req: &Request<T>) -> HashMap<String, String> {
+    let decode = |s: &str| {
+        let s = s.replace('+', " ");
+        percent_decode(s.as_bytes())
+            .decode_utf8_lossy()
+            .into_owned()
+    };
+    req.uri()
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .filter(|p| !p.is_empty())
+        .map(|p| {
+            let mut parts = p.splitn(2, '=');
+            let key = decode(parts.next().unwrap_or_default());
+            let value = decode(parts.next().unwrap_or_default());
+            (key, value)
+        })
+        .collect()
 }
 
 /// Handle a web request asynchronously.
@@ -52,35 +189,69 @@ pub async fn handle_request<T>(
     req: Request<T>,
     client: &Client,
     config: &ServerConfig,
+    events: &EventBus,
 ) -> Result<Response<Body>, Error> {
     match req.uri().path() {
         // JSON API with the status of the network.
         "/data" => {
+            // if the device cannot be inspected, fall back to the status last published by this
+            // server
+            let peers = match get_peers_status(config).await {
+                Ok(peers) => peers,
+                Err(e) => {
+                    warn!("Failed to get the live status of the peers: {:?}", e);
+                    schema::get_peer_status(client)
+                        .await?
+                        .into_iter()
+                        .filter(|p| p.server == config.name)
+                        .map(|p| p.status)
+                        .collect()
+                }
+            };
+            let peers: HashMap<_, _> = peers
+                .into_iter()
+                .map(|p| (p.public_key.clone(), p))
+                .collect();
             let servers = schema::get_servers(client)
                 .await?
                 .into_iter()
                 .map(|s| NetworkStatusServer {
-                    name: s.name,
+                    status: peers.get(&s.public_key).map(NetworkStatusPeer::from),
+                    name: s.name.clone(),
                     subnet: s.subnet_addr.to_string(),
                     subnet_len: s.subnet_len,
                     address: s.address.to_string(),
-                    endpoint: s.public_address.to_string(),
+                    secondary_subnet: s.secondary_subnet_addr.map(|a| a.to_string()),
+                    secondary_subnet_len: s.secondary_subnet_len,
+                    secondary_address: s.secondary_address.map(|a| a.to_string()),
+                    endpoint: s.public_host().unwrap_or_default(),
                     endpoint_port: s.public_port,
                 })
                 .collect();
-            let clients = schema::get_clients(client, None::<&str>)
+            let clients = schema::get_all_clients(client)
                 .await?
                 .into_iter()
                 .map(|c| NetworkStatusClient {
+                    priority: c.priority,
+                    suspended: !c.enabled,
+                    suspended_reason: c.disabled_reason,
+                    suspended_at: c.disabled_at,
+                    status: if c.server == config.name {
+                        peers.get(&c.client.public_key).map(NetworkStatusPeer::from)
+                    } else {
+                        None
+                    },
                     name: c.client.name,
                     server: c.server,
                     address: c.address.to_string(),
+                    secondary_address: c.secondary_address.map(|a| a.to_string()),
                 })
                 .collect();
             let status = NetworkStatus {
                 servers,
                 clients,
                 base_domain: config.base_domain.clone(),
+                local_server: config.name.clone(),
             };
             Ok(Response::builder()
                 .status(200)
@@ -88,13 +259,142 @@ pub async fn handle_request<T>(
                 .body(Body::from(serde_json::to_string_pretty(&status)?))
                 .unwrap())
         }
-        // Generate the client configuration for a given username.
+        // JSON API with the status of the entire network, as published by all the servers.
+        "/network" => {
+            let published = schema::get_peer_status(client).await?;
+            let published: HashMap<_, _> = published
+                .iter()
+                .map(|p| ((p.server.as_str(), p.status.public_key.as_str()), p))
+                .collect();
+            // a status is stale if its server missed some publications
+            let max_age = 3 * config.status_interval().as_secs();
+            let servers = schema::get_servers(client).await?;
+            let topology = Topology::new(&servers, &schema::get_server_links(client).await?);
+            let mut links = Vec::new();
+            for from in &servers {
+                for to in &servers {
+                    if !topology.neighbors(&from.name).contains(&to.name.as_str()) {
+                        continue;
+                    }
+                    let status = published.get(&(from.name.as_str(), to.public_key.as_str()));
+                    links.push(NetworkLinkStatus {
+                        from: from.name.clone(),
+                        to: to.name.clone(),
+                        status: status.map(|p| NetworkStatusPeer::from(&p.status)),
+                        stale: status.map(|p| p.age > max_age).unwrap_or(false),
+                    });
+                }
+            }
+            let clients = schema::get_clients(client, None::<&str>)
+                .await?
+                .into_iter()
+                .map(|c| {
+                    let status = published.get(&(c.server.as_str(), c.client.public_key.as_str()));
+                    NetworkClientStatus {
+                        status: status.map(|p| NetworkStatusPeer::from(&p.status)),
+                        stale: status.map(|p| p.age > max_age).unwrap_or(false),
+                        name: c.client.name,
+                        server: c.server,
+                        address: c.address.to_string(),
+                    }
+                })
+                .collect();
+            let overview = NetworkOverview {
+                links,
+                clients,
+                base_domain: config.base_domain.clone(),
+            };
+            Ok(Response::builder()
+                .status(200)
+                .header("Content-Type", "application/json")
+                .body(Body::from(serde_json::to_string_pretty(&overview)?))
+                .unwrap())
+        }
+        // JSON API with the traffic of the clients, day by day. The range of days and the client can
+        // be selected with the `from`, `to` and `client` query parameters.
+        "/usage" => {
+            let mut params = query_params(&req);
+            let from = params.remove("from");
+            let to = params.remove("to");
+            for day in from.iter().chain(to.iter()) {
+                if let Err(err) = schema::validate_day(day) {
+                    return Ok(Response::builder()
+                        .status(400)
+                        .body(Body::from(err.to_string()))
+                        .unwrap());
+                }
+            }
+            let usage: Vec<_> = schema::get_traffic(client, from, to, params.remove("client"))
+                .await?
+                .into_iter()
+                .map(|u| TrafficUsageEntry {
+                    day: u.day,
+                    client: u.client,
+                    server: u.server,
+                    transfer_rx: u.transfer_rx,
+                    transfer_tx: u.transfer_tx,
+                })
+                .collect();
+            Ok(Response::builder()
+                .status(200)
+                .header("Content-Type", "application/json")
+                .body(Body::from(serde_json::to_string_pretty(&usage)?))
+                .unwrap())
+        }
+        // Server-Sent Events stream with the events of this server.
+        "/events" => {
+            let stream = events
+                .subscribe()
+                .map(|e| Ok::<_, std::io::Error>(e.to_sse()));
+            Ok(Response::builder()
+                .status(200)
+                .header("Content-Type", "text/event-stream")
+                .header("Cache-Control", "no-cache")
+                .body(Body::wrap_stream(stream))
+                .unwrap())
+        }
+        // Generate the client configuration for a given username. The preferred server can be
+        // changed with the `server` query parameter and the format with the `format` one.
         url if url.starts_with("/conf/") => {
-            let name = &url[6..];
-            let conf = gen_client_config(config, client, name.to_owned(), None).await;
+            let name = match url[6..].parse::<DnsLabel>() {
+                Ok(name) => name,
+                Err(err) => {
+                    return Ok(Response::builder()
+                        .status(400)
+                        .body(Body::from(err.to_string()))
+                        .unwrap())
+                }
+            };
+            let mut params = query_params(&req);
+            let format = match params.remove("format").map(|f| f.parse::<ClientFormat>()) {
+                Some(Ok(format)) => format,
+                Some(Err(err)) => {
+                    return Ok(Response::builder()
+                        .status(400)
+                        .body(Body::from(err.to_string()))
+                        .unwrap())
+                }
+                None => ClientFormat::default(),
+            };
+            let primary = params.remove("server");
+            let conf = gen_client_config(
+                config,
+                client,
+                name,
+                None,
+                &BTreeMap::new(),
+                primary,
+                format,
+            )
+            .await;
+            let content_type = match format {
+                ClientFormat::Json => "application/json",
+                _ => "text/plain",
+            };
             match conf {
                 Ok(conf) => Ok(Response::builder()
                     .status(200)
+                    .header("Content-Type", content_type)
                     .body(Body::from(conf))
                     .unwrap()),
                 Err(err) => Ok(Response::builder()
@@ -132,3 +432,41 @@ pub async fn handle_request<T>(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(uri: &str) -> HashMap<String, String> {
+        query_params(&Request::builder().uri(uri).body(()).unwrap())
+    }
+
+    #[test]
+    fn test_query_params_usage() {
+        let usage = params("/usage?from=2019%2D10%2D01&to=2019-10-31&client=&x");
+        assert_eq!(usage["from"], "2019-10-01");
+        assert_eq!(usage["to"], "2019-10-31");
+        assert_eq!(usage["client"], "");
+        assert_eq!(usage["x"], "");
+        assert!(!usage.contains_key("y"));
+        // the invalid encodings are kept instead of failing
+        assert_eq!(params("/usage?from=%zz+%E2%82")["from"], "%zz \u{fffd}");
+    }
+
+    #[test]
+    fn test_query_params_server() {
+        // the preferred server is passed as it's written in the links
+        let conf = params("/conf/client1?server=srv%2D1");
+        assert_eq!(conf["server"], "srv-1");
+    }
+
+    #[test]
+    fn test_query_params_format() {
+        let conf = params("/conf/client1?format=wg%2Dquick&server=srv1");
+        assert_eq!(
+            conf["format"].parse::<ClientFormat>().unwrap(),
+            ClientFormat::WgQuick
+        );
+        assert_eq!(conf["server"], "srv1");
+    }
+}
@@ -0,0 +1,203 @@
+use failure::{bail, Error};
+use futures::StreamExt;
+use hmac::{Hmac, Mac};
+use hyper::client::HttpConnector;
+use hyper::{Body, Request};
+use serde::Serialize;
+use sha2::Sha256;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::timer::delay_for;
+
+use crate::config::{ServerConfig, WebhookConfig};
+use crate::events::{Event, EventBus};
+
+/// Maximum number of attempts for delivering an event to a webhook.
+const MAX_ATTEMPTS: u32 = 5;
+/// Delay before retrying a failed delivery, doubled at every attempt.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The body of the requests sent to the webhooks.
+#[derive(Debug, Serialize)]
+struct WebhookPayload<'a> {
+    /// The name of the server that produced the event.
+    server: &'a str,
+    /// The UNIX timestamp of the delivery.
+    timestamp: u64,
+    /// The event.
+    event: &'a Event,
+}
+
+/// Spawn a task for each webhook in the configuration, delivering to it the events published on
+/// the bus, one at the time.
+pub fn spawn_webhooks(config: &ServerConfig, events: &EventBus) {
+    for webhook in &config.webhooks {
+        let mut rx = events.subscribe();
+        let webhook = webhook.clone();
+        let server = config.name.clone();
+        let http = hyper::Client::new();
+        info!("Sending the events to the webhook {}", webhook.url);
+        tokio::spawn(async move {
+            while let Some(event) = rx.next().await {
+                let selected = match &webhook.events {
+                    Some(events) => events.iter().any(|e| e == event.name()),
+                    None => event.is_notable(),
+                };
+                if selected {
+                    deliver(&http, &server, &webhook, &event).await;
+                }
+            }
+        });
+    }
+}
+
+/// Deliver an event to a webhook, retrying with an exponential backoff.
+async fn deliver(
+    http: &hyper::Client<HttpConnector>,
+    server: &str,
+    webhook: &WebhookConfig,
+    event: &Event,
+) {
+    let mut backoff = INITIAL_BACKOFF;
+    for attempt in 1..=MAX_ATTEMPTS {
+        match send(http, server, webhook, event).await {
+            Ok(_) => {
+                debug!("Event {} delivered to {}", event.name(), webhook.url);
+                return;
+            }
+            Err(e) => warn!(
+                "Failed to deliver event {} to {} (attempt {}/{}): {:?}",
+                event.name(),
+                webhook.url,
+                attempt,
+                MAX_ATTEMPTS,
+                e
+            ),
+        }
+        if attempt < MAX_ATTEMPTS {
+            delay_for(backoff).await;
+            backoff *= 2;
+        }
+    }
+    error!(
+        "Giving up delivering event {} to {}",
+        event.name(),
+        webhook.url
+    );
+}
+
+/// Send a single request to a webhook.
+async fn send(
+    http: &hyper::Client<HttpConnector>,
+    server: &str,
+    webhook: &WebhookConfig,
+    event: &Event,
+) -> Result<(), Error> {
+    let payload = WebhookPayload {
+        server,
+        timestamp: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        event,
+    };
+    let body = serde_json::to_vec(&payload)?;
+    let mut request = Request::post(webhook.url.as_str());
+    request
+        .header("Content-Type", "application/json")
+        .header("X-Wireguard-Manager-Event", event.name());
+    if let Some(secret) = &webhook.secret {
+        request.header(
+            "X-Wireguard-Manager-Signature",
+            sign(secret, &body).as_str(),
+        );
+    }
+    let response = http.request(request.body(Body::from(body))?).await?;
+    if !response.status().is_success() {
+        bail!("Webhook responded with {}", response.status());
+    }
+    Ok(())
+}
+
+/// Compute the signature of the body of a request, in the form `sha256=<hex digest>`.
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_varkey(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.input(body);
+    format!("sha256={}", hex::encode(mac.result().code()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::channel::mpsc;
+    use futures::{FutureExt, TryStreamExt};
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::Response;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_sign() {
+        // test case 2 of RFC 4231
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_deliver_to_stub() {
+        // a stub that fails the first request and records all of them
+        let (tx, mut rx) = mpsc::unbounded();
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let service = make_service_fn(move |_| {
+            let tx = tx.clone();
+            let attempts = attempts.clone();
+            async move {
+                Ok::<_, Error>(service_fn(move |req: Request<Body>| {
+                    let tx = tx.clone();
+                    let attempts = attempts.clone();
+                    async move {
+                        let header = |name| {
+                            req.headers()
+                                .get(name)
+                                .map(|h| h.to_str().unwrap().to_string())
+                        };
+                        let event = header("X-Wireguard-Manager-Event");
+                        let signature = header("X-Wireguard-Manager-Signature");
+                        let body = req.into_body().try_concat().await?.to_vec();
+                        tx.unbounded_send((event, signature, body)).unwrap();
+                        let status = match attempts.fetch_add(1, Ordering::SeqCst) {
+                            0 => 500,
+                            _ => 200,
+                        };
+                        Ok::<_, Error>(Response::builder().status(status).body(Body::empty())?)
+                    }
+                }))
+            }
+        });
+        let server = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(service);
+        let addr = server.local_addr();
+        tokio::spawn(server.map(|_| ()));
+
+        let webhook = WebhookConfig {
+            url: format!("http://{}/hook", addr),
+            secret: Some("secret".to_string()),
+            events: None,
+        };
+        let event = Event::ClientAdded {
+            name: "client1".to_string(),
+        };
+        deliver(&hyper::Client::new(), "server1", &webhook, &event).await;
+
+        // the first attempt fails and it's retried
+        let (_, _, first) = rx.try_next().unwrap().unwrap();
+        let (name, signature, body) = rx.try_next().unwrap().unwrap();
+        assert!(rx.try_next().is_err());
+        assert_eq!(name.as_deref(), Some("client_added"));
+        assert_eq!(signature, Some(sign("secret", &body)));
+        let payload: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(payload["server"], "server1");
+        assert_eq!(payload["event"]["type"], "client_added");
+        assert_eq!(payload["event"]["name"], "client1");
+        let first: serde_json::Value = serde_json::from_slice(&first).unwrap();
+        assert_eq!(first["event"], payload["event"]);
+    }
+}
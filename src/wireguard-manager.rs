@@ -4,25 +4,50 @@ extern crate lazy_static;
 extern crate log;
 
 use crate::config::ServerConfig;
+use crate::dns_server::DnsZone;
+use crate::endpoints::EndpointResolver;
+use crate::events::{Event, EventBus};
+use crate::status::{ClientTracker, PeerTracker};
+use crate::traffic::TrafficAccounting;
 use failure::Error;
 use futures::future;
 use futures::future::Ready;
+use futures::lock::Mutex;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::Server;
 use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
 use std::sync::Arc;
 use tokio::prelude::*;
+use tokio::timer::{delay_for, Interval};
 use tokio_net::signal;
 use tokio_net::signal::unix::SignalKind;
 use tokio_postgres::{AsyncMessage, Client};
 
+pub mod client_config;
 pub mod config;
 pub mod dns;
+pub mod dns_backends;
+pub mod dns_server;
+pub mod endpoints;
+pub mod events;
+pub mod firewall;
+pub mod preshared_keys;
+pub mod rotation;
 pub mod schema;
+pub mod status;
+pub mod topology;
+pub mod traffic;
 pub mod web;
+pub mod webhooks;
 pub mod wireguard;
 
+lazy_static! {
+    /// Serializes the updates of the server, triggered at the same time by the database
+    /// notifications, the changes of validity and SIGUSR1.
+    static ref UPDATE_LOCK: Mutex<()> = Mutex::new(());
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Error> {
     env_logger::init();
@@ -32,6 +57,7 @@ async fn main() -> Result<(), Error> {
     // Exit tearing down on Control-C.
     let config2 = config.clone();
     tokio::spawn(signal::ctrl_c()?.for_each(move |_| -> Ready<()> {
+        firewall::unsetup_firewall(&config2);
         if let Err(e) = wireguard::unsetup_server(&config2) {
             error!("Error tearing down the server: {:?}", e);
             std::process::exit(1);
@@ -47,15 +73,52 @@ async fn main() -> Result<(), Error> {
     let client_arc = Arc::new(client);
     let client = client_arc.as_ref();
 
+    let traffic_arc = Arc::new(TrafficAccounting::default());
+    let traffic = traffic_arc.as_ref();
+
+    let events_arc = Arc::new(EventBus::default());
+    let events = events_arc.as_ref();
+    webhooks::spawn_webhooks(&config, events);
+
+    let clients_arc = Arc::new(ClientTracker::default());
+    let clients = clients_arc.as_ref();
+
+    let endpoints_arc = Arc::new(EndpointResolver::default());
+    let endpoints = endpoints_arc.as_ref();
+
+    let zone_arc = Arc::new(DnsZone::default());
+    let zone = zone_arc.as_ref();
+
     // Reload the configuration from the DB on SIGUSR1.
     let client_arc2 = client_arc.clone();
+    let traffic_arc2 = traffic_arc.clone();
+    let events_arc2 = events_arc.clone();
+    let clients_arc2 = clients_arc.clone();
+    let endpoints_arc2 = endpoints_arc.clone();
+    let zone_arc2 = zone_arc.clone();
     let config3 = config.clone();
     tokio::spawn(
         signal::unix::signal(SignalKind::user_defined1())?.for_each(move |_| {
             info!("Reloading due to SIGUSR1");
             let config = config3.clone();
             let client = client_arc2.clone();
-            async move { update_server(&config, client.as_ref()).await }
+            let traffic = traffic_arc2.clone();
+            let events = events_arc2.clone();
+            let clients = clients_arc2.clone();
+            let endpoints = endpoints_arc2.clone();
+            let zone = zone_arc2.clone();
+            async move {
+                update_server(
+                    &config,
+                    client.as_ref(),
+                    traffic.as_ref(),
+                    events.as_ref(),
+                    clients.as_ref(),
+                    endpoints.as_ref(),
+                    zone.as_ref(),
+                )
+                .await
+            }
         }),
     );
 
@@ -65,10 +128,98 @@ async fn main() -> Result<(), Error> {
     // Initial server setup
     wireguard::setup_server(&config).await?;
     info!("Server setup done");
-    update_server(&config, &client).await;
+    update_server(&config, client, traffic, events, clients, endpoints, zone).await;
+
+    // Periodically publish the status of the peers in the database and account their traffic.
+    let client_arc3 = client_arc.clone();
+    let traffic_arc3 = traffic_arc.clone();
+    let events_arc3 = events_arc.clone();
+    let tracker_arc = Arc::new(PeerTracker::default());
+    let config4 = config.clone();
+    tokio::spawn(
+        Interval::new_interval(config.status_interval()).for_each(move |_| {
+            let config = config4.clone();
+            let client = client_arc3.clone();
+            let traffic = traffic_arc3.clone();
+            let events = events_arc3.clone();
+            let tracker = tracker_arc.clone();
+            async move {
+                let client = client.as_ref();
+                if let Err(e) =
+                    status::publish_status(&config, client, tracker.as_ref(), events.as_ref()).await
+                {
+                    error!("Failed to publish the status of the peers: {:?}", e);
+                }
+                if let Err(e) = traffic.sample(&config, client).await {
+                    error!("Failed to account the traffic of the peers: {:?}", e);
+                }
+            }
+        }),
+    );
+
+    // Periodically resolve the hostnames of the other servers, following their changes of address.
+    let client_arc4 = client_arc.clone();
+    let endpoints_arc3 = endpoints_arc.clone();
+    let config5 = config.clone();
+    tokio::spawn(
+        Interval::new_interval(config.resolve_interval()).for_each(move |_| {
+            let config = config5.clone();
+            let client = client_arc4.clone();
+            let endpoints = endpoints_arc3.clone();
+            async move {
+                if let Err(e) = endpoints.refresh(&config, client.as_ref()).await {
+                    error!("Failed to update the endpoints of the servers: {:?}", e);
+                }
+            }
+        }),
+    );
+
+    // Reconcile again when a client or a connection becomes valid or expires. The next change is
+    // checked periodically since it may change with the database.
+    let client_arc5 = client_arc.clone();
+    let traffic_arc4 = traffic_arc.clone();
+    let events_arc4 = events_arc.clone();
+    let clients_arc3 = clients_arc.clone();
+    let endpoints_arc4 = endpoints_arc.clone();
+    let zone_arc3 = zone_arc.clone();
+    let config6 = config.clone();
+    tokio::spawn(async move {
+        let client = client_arc5.as_ref();
+        loop {
+            let next = match schema::get_next_validity_change(client).await {
+                Ok(next) => next,
+                Err(e) => {
+                    error!("Failed to get the next expiration: {:?}", e);
+                    None
+                }
+            };
+            match next {
+                Some(next) if next <= config6.status_interval() => {
+                    delay_for(next).await;
+                    info!("Reloading due to a change of validity");
+                    update_server(
+                        &config6,
+                        client,
+                        traffic_arc4.as_ref(),
+                        events_arc4.as_ref(),
+                        clients_arc3.as_ref(),
+                        endpoints_arc4.as_ref(),
+                        zone_arc3.as_ref(),
+                    )
+                    .await;
+                }
+                _ => delay_for(config6.status_interval()).await,
+            }
+        }
+    });
 
     // Spawn the web server for the network statistics
-    spawn_web_server(&config, client_arc.clone())?;
+    spawn_web_server(&config, client_arc.clone(), events_arc.clone())?;
+
+    // Spawn the built-in DNS server, if enabled
+    if config.dns_listen_address.is_some() {
+        dns_server::spawn_dns_server(&config, zone_arc.clone()).await?;
+    }
 
     // Listen for server notifications
     rx.filter_map(|m| match m {
@@ -77,25 +228,60 @@ async fn main() -> Result<(), Error> {
     })
     .for_each(|m| {
         info!("Database update notification: {:?}", m);
-        update_server(&config, &client)
+        events.publish(Event::DatabaseNotification {
+            channel: m.channel().to_string(),
+            payload: m.payload().to_string(),
+        });
+        update_server(&config, client, traffic, events, clients, endpoints, zone)
     })
     .await;
     Ok(())
 }
 
-/// Update the server, first updating wireguard and then the DNS.
-async fn update_server(config: &ServerConfig, client: &Client) {
+/// Update the server, first updating wireguard, then the firewall and then the DNS.
+async fn update_server(
+    config: &ServerConfig,
+    client: &Client,
+    traffic: &TrafficAccounting,
+    events: &EventBus,
+    clients: &ClientTracker,
+    endpoints: &EndpointResolver,
+    zone: &DnsZone,
+) {
+    let _guard = UPDATE_LOCK.lock().await;
     info!("Updating server configuration");
-    wireguard::update_server(&config, &client)
-        .map(|res| res.unwrap())
-        .await;
-    dns::update_dns(&config, &client)
-        .map(|res| res.unwrap())
-        .await;
+    let result = wireguard::update_server(config, client, endpoints, traffic).await;
+    let result = match result {
+        Ok(_) => firewall::update_firewall(config, client).await,
+        Err(e) => Err(e),
+    };
+    let result = match result {
+        Ok(_) => dns::update_dns(config, client, zone).await,
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(_) => {
+            info!("Server configuration updated");
+            events.publish(Event::ReconciliationCompleted);
+            if let Err(e) = clients.check(config, client, events).await {
+                warn!("Failed to check the added and removed clients: {:?}", e);
+            }
+        }
+        Err(e) => {
+            error!("Failed to update the server configuration: {:?}", e);
+            events.publish(Event::ReconciliationFailed {
+                error: e.to_string(),
+            });
+        }
+    }
 }
 
 /// Spawn the web server and listen to the port specified in the configuration file.
-fn spawn_web_server(config: &ServerConfig, client_arc: Arc<Client>) -> Result<(), Error> {
+fn spawn_web_server(
+    config: &ServerConfig,
+    client_arc: Arc<Client>,
+    events_arc: Arc<EventBus>,
+) -> Result<(), Error> {
     let addr = SocketAddr::new(
         IpAddr::from_str(&config.web_listen_address)?,
         config.web_listen_port,
@@ -103,13 +289,18 @@ fn spawn_web_server(config: &ServerConfig, client_arc: Arc<Client>) -> Result<()
     let config = Arc::new(config.clone());
     let service = make_service_fn(move |_| {
         let client_arc = client_arc.clone();
+        let events_arc = events_arc.clone();
         let config = config.clone();
 
         async move {
             Ok::<_, Error>(service_fn(move |req| {
                 let client = client_arc.clone();
+                let events = events_arc.clone();
                 let config = config.clone();
-                async move { web::handle_request(req, client.as_ref(), config.as_ref()).await }
+                async move {
+                    web::handle_request(req, client.as_ref(), config.as_ref(), events.as_ref())
+                        .await
+                }
             }))
         }
     });
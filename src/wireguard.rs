@@ -1,20 +1,35 @@
 use std::process::Stdio;
 
-use failure::{bail, Error};
+use failure::{bail, format_err, Error};
 use regex::Regex;
 use tempfile::NamedTempFile;
 use tokio::net::process::Command;
 use tokio_postgres::Client;
 
+use crate::client_config::{ClientConfig, ClientFormat, ClientPeer};
 use crate::config::ServerConfig;
+use crate::endpoints::EndpointResolver;
+use crate::preshared_keys;
+use crate::rotation;
 use crate::schema;
-use crate::schema::{ClientConnection, Server};
+use crate::schema::{
+    ClientConnection, DnsLabel, PeerStatus, RoutedSubnet, Server, ServerConnection,
+};
+use crate::topology::{aggregate, Topology};
+use crate::traffic::TrafficAccounting;
+use std::collections::{BTreeMap, HashMap};
 use std::net::IpAddr;
 use std::str::FromStr;
 
+/// The protocol the routes added to the routing table are tagged with, for distinguishing them
+/// from the ones added by someone else.
+const ROUTE_PROTOCOL: &str = "77";
+
 lazy_static! {
     /// Search for the ip addresses of the network interface.
     static ref RE: Regex = Regex::new(r"inet6? ([^\s]+)/(\d+)").unwrap();
+    /// Search for the MTU of the network interface.
+    static ref MTU_RE: Regex = Regex::new(r"mtu (\d+)").unwrap();
 }
 
 /// Setup the server's wireguard configuration.
@@ -41,10 +56,61 @@ pub fn unsetup_server(config: &ServerConfig) -> Result<(), Error> {
     }
 }
 
+/// Fetch the live status of all the peers of the wireguard device, using `wg show dump`.
+pub async fn get_peers_status(config: &ServerConfig) -> Result<Vec<PeerStatus>, Error> {
+    let dump = Command::new("wg")
+        .args(&["show", &config.device_name, "dump"])
+        .output()
+        .await?;
+    if !dump.status.success() {
+        bail!("Failed to get the status of the peers: {:?}", dump);
+    }
+    parse_peers_status(&String::from_utf8_lossy(&dump.stdout))
+}
+
+/// Parse the output of `wg show dump`.
+fn parse_peers_status(dump: &str) -> Result<Vec<PeerStatus>, Error> {
+    let mut peers = Vec::new();
+    // the first line is about the interface itself, the following ones are the peers with the
+    // fields: public-key, preshared-key, endpoint, allowed-ips, latest-handshake, transfer-rx,
+    // transfer-tx, persistent-keepalive
+    for line in dump.lines().skip(1) {
+        let fields: Vec<_> = line.split('\t').collect();
+        if fields.len() < 8 {
+            bail!("Malformed line in wg dump: {}", line);
+        }
+        let endpoint = match fields[2] {
+            "(none)" => None,
+            endpoint => Some(endpoint.to_string()),
+        };
+        let latest_handshake = match u64::from_str(fields[4])? {
+            0 => None,
+            handshake => Some(handshake),
+        };
+        peers.push(PeerStatus {
+            public_key: fields[0].to_string(),
+            endpoint,
+            latest_handshake,
+            transfer_rx: u64::from_str(fields[5])?,
+            transfer_tx: u64::from_str(fields[6])?,
+        });
+    }
+    Ok(peers)
+}
+
 /// Update the wireguard server configuration.
-pub async fn update_server(config: &ServerConfig, client: &Client) -> Result<(), Error> {
-    ensure_conf(config, client).await?;
+pub async fn update_server(
+    config: &ServerConfig,
+    client: &Client,
+    endpoints: &EndpointResolver,
+    traffic: &TrafficAccounting,
+) -> Result<(), Error> {
+    rotation::update_key_rotation(config, client).await?;
+    ensure_conf(config, client, endpoints, traffic).await?;
+    rotation::acknowledge_keys(config, client).await?;
+    ensure_mtu(config, client).await?;
     ensure_ip(config, client).await?;
+    ensure_routes(config, client).await?;
     Ok(())
 }
 
@@ -91,7 +157,7 @@ async fn make_interface(config: &ServerConfig) -> Result<(), Error> {
     }
 }
 
-/// Make sure the interface has the correct ip addresses.
+/// Make sure the interface has the correct ip addresses, one for each IP family of the network.
 async fn ensure_ip(config: &ServerConfig, client: &Client) -> Result<(), Error> {
     let servers = schema::get_servers(&client).await?;
     let server = servers
@@ -106,15 +172,27 @@ async fn ensure_ip(config: &ServerConfig, client: &Client) -> Result<(), Error>
         bail!("Failed to get ips of interface: {:?}", ips);
     }
     let stdout = String::from_utf8_lossy(&ips.stdout);
-    let mut present = false; // whether the correct address is already present
-                             // need to collect the results because the regex state cannot be sent between thread, and this
-                             // doesn't play well with the await inside the loop.
+    let expected = server
+        .addresses()
+        .into_iter()
+        .map(|addr| match config.netmask_len_of(&addr) {
+            Some(len) => Ok((addr, len)),
+            None => Err(format_err!(
+                "No network configured for the address {}",
+                addr
+            )),
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    // the correct addresses already present
+    let mut present = Vec::new();
+    // need to collect the results because the regex state cannot be sent between thread, and this
+    // doesn't play well with the await inside the loop.
     let ips: Vec<_> = RE.captures_iter(&stdout).collect();
     for ip in ips.into_iter() {
         let addr = IpAddr::from_str(&ip[1])?;
         let len = u8::from_str(&ip[2])?;
         // wrong ip or wrong network length
-        if addr != server.address || len != config.netmask_len {
+        if !expected.contains(&(addr, len)) {
             warn!(
                 "Wrong address {}/{} found in {}, removing it",
                 addr.to_string(),
@@ -123,22 +201,70 @@ async fn ensure_ip(config: &ServerConfig, client: &Client) -> Result<(), Error>
             );
             remove_ip(config, addr, len).await?;
         } else {
-            present = true;
+            present.push((addr, len));
         }
     }
-    // address is not already present, add it
-    if !present {
+    // addresses not already present, add them
+    for (addr, len) in expected {
+        if present.contains(&(addr, len)) {
+            continue;
+        }
         info!(
             "Adding address {}/{} to device {}",
-            server.address.to_string(),
-            config.netmask_len,
+            addr.to_string(),
+            len,
             config.device_name
         );
-        add_ip(config, server.address, config.netmask_len).await?;
+        add_ip(config, addr, len).await?;
     }
     Ok(())
 }
 
+/// Make sure the interface has the MTU of the server, if one is configured. Otherwise the MTU is
+/// left untouched, as chosen when the interface was created or by the administrator.
+async fn ensure_mtu(config: &ServerConfig, client: &Client) -> Result<(), Error> {
+    let servers = schema::get_servers(client).await?;
+    let server = servers
+        .iter()
+        .find(|s| s.name == config.name)
+        .expect("Server is not registered in the db");
+    let expected = match server.mtu {
+        Some(mtu) => mtu,
+        None => return Ok(()),
+    };
+    let link = Command::new("ip")
+        .args(&["link", "show", "dev", &config.device_name])
+        .output()
+        .await?;
+    if !link.status.success() {
+        bail!("Failed to get the MTU of the interface: {:?}", link);
+    }
+    let stdout = String::from_utf8_lossy(&link.stdout);
+    let current = match MTU_RE.captures(&stdout) {
+        Some(mtu) => Some(u32::from_str(&mtu[1])?),
+        None => None,
+    };
+    if current == Some(expected) {
+        return Ok(());
+    }
+    let mtu = expected.to_string();
+    let cmd = Command::new("ip")
+        .args(&["link", "set", "dev", &config.device_name, "mtu", &mtu])
+        .spawn()?
+        .await?;
+    if cmd.success() {
+        info!("MTU of {} set to {}", config.device_name, mtu);
+        Ok(())
+    } else {
+        bail!(
+            "Failed to set the MTU of {} to {}: exit code {:?}",
+            config.device_name,
+            mtu,
+            cmd.code()
+        );
+    }
+}
+
 /// Remove an ip address from the network device.
 async fn remove_ip(config: &ServerConfig, address: IpAddr, len: u8) -> Result<(), Error> {
     let addr = format!("{}/{}", address.to_string(), len);
@@ -191,129 +317,901 @@ async fn add_ip(config: &ServerConfig, address: IpAddr, len: u8) -> Result<(), E
     }
 }
 
-/// Build the last version of the wireguard configuration and use it.
-async fn ensure_conf(config: &ServerConfig, client: &Client) -> Result<(), Error> {
-    let server_config = gen_server_config(config, client).await?;
-    debug!("Wireguard configuration is:\n{}", server_config);
-    let tmpfile = NamedTempFile::new()?;
-    tokio::fs::write(tmpfile.path().to_path_buf(), server_config.as_bytes()).await?;
-    let child = Command::new("wg")
-        .arg("setconf")
-        .arg(&config.device_name)
-        .arg(tmpfile.path())
+/// Make sure there is a route through the device for all the reachable subnets routed through the
+/// clients.
+async fn ensure_routes(config: &ServerConfig, client: &Client) -> Result<(), Error> {
+    let servers = schema::get_servers(client).await?;
+    let links = schema::get_server_links(client).await?;
+    let next_hops = Topology::new(&servers, &links).next_hops(&config.name);
+    let expected: Vec<_> = schema::get_routed_subnets(client)
+        .await?
+        .into_iter()
+        .filter(|s| s.server == config.name || next_hops.contains_key(&s.server))
+        .map(|s| (s.subnet_addr, s.subnet_len))
+        .collect();
+    let present = get_routes(config).await?;
+    for (address, len) in &present {
+        if !expected.contains(&(*address, *len)) {
+            warn!(
+                "Unexpected route to {}/{} found in {}, removing it",
+                address.to_string(),
+                len,
+                config.device_name
+            );
+            change_route(config, "delete", *address, *len).await?;
+        }
+    }
+    for (address, len) in &expected {
+        if !present.contains(&(*address, *len)) {
+            change_route(config, "add", *address, *len).await?;
+        }
+    }
+    Ok(())
+}
+
+/// List the routes through the network device added by the manager.
+async fn get_routes(config: &ServerConfig) -> Result<Vec<(IpAddr, u8)>, Error> {
+    let mut routes = Vec::new();
+    for family in &["-4", "-6"] {
+        let output = Command::new("ip")
+            .args(&[
+                *family,
+                "route",
+                "show",
+                "dev",
+                &config.device_name,
+                "proto",
+                ROUTE_PROTOCOL,
+            ])
+            .output()
+            .await?;
+        if !output.status.success() {
+            bail!("Failed to get the routes of the interface: {:?}", output);
+        }
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        // each line starts with the destination, without the length if it's a single host
+        for destination in stdout.lines().filter_map(|l| l.split_whitespace().next()) {
+            let mut parts = destination.splitn(2, '/');
+            let address = IpAddr::from_str(parts.next().unwrap_or_default())?;
+            let len = match parts.next() {
+                Some(len) => u8::from_str(len)?,
+                None if address.is_ipv4() => 32,
+                None => 128,
+            };
+            routes.push((address, len));
+        }
+    }
+    Ok(routes)
+}
+
+/// Add or delete a route through the network device.
+async fn change_route(
+    config: &ServerConfig,
+    action: &str,
+    address: IpAddr,
+    len: u8,
+) -> Result<(), Error> {
+    let destination = format!("{}/{}", address, len);
+    let cmd = Command::new("ip")
+        .args(&[
+            "route",
+            action,
+            &destination,
+            "dev",
+            &config.device_name,
+            "proto",
+            ROUTE_PROTOCOL,
+        ])
         .spawn()?
         .await?;
-    if child.success() {
-        info!("Wireguard configuration updated successfully");
+    if cmd.success() {
+        let done = if action == "add" { "added" } else { "removed" };
+        info!(
+            "Route to {} through {} {}",
+            destination, config.device_name, done
+        );
         Ok(())
     } else {
-        bail!("Wireguard failed with {:?}", child.code());
+        bail!(
+            "Failed to {} the route to {} through {}: exit code {:?}",
+            action,
+            destination,
+            config.device_name,
+            cmd.code()
+        );
     }
 }
 
+/// Build the last version of the wireguard configuration and use it. Applying the configuration
+/// resets the transfer counters of the peers, so their traffic is accounted around it.
+async fn ensure_conf(
+    config: &ServerConfig,
+    client: &Client,
+    endpoints: &EndpointResolver,
+    traffic: &TrafficAccounting,
+) -> Result<(), Error> {
+    let server_config = gen_server_config(config, client, endpoints).await?;
+    debug!("Wireguard configuration is:\n{}", server_config);
+    let tmpfile = NamedTempFile::new()?;
+    tokio::fs::write(tmpfile.path().to_path_buf(), server_config.as_bytes()).await?;
+    traffic
+        .around_reset(config, client, async {
+            let child = Command::new("wg")
+                .arg("setconf")
+                .arg(&config.device_name)
+                .arg(tmpfile.path())
+                .spawn()?
+                .await?;
+            if child.success() {
+                info!("Wireguard configuration updated successfully");
+                Ok(())
+            } else {
+                bail!("Wireguard failed with {:?}", child.code());
+            }
+        })
+        .await
+}
+
 /// Generate the configuration of this server fetching its configuration from the database.
-async fn gen_server_config(config: &ServerConfig, client: &Client) -> Result<String, Error> {
+async fn gen_server_config(
+    config: &ServerConfig,
+    client: &Client,
+    endpoints: &EndpointResolver,
+) -> Result<String, Error> {
     let servers = schema::get_servers(&client).await?;
     let server = servers
         .iter()
         .find(|s| s.name == config.name)
         .expect("Server is not registered in the db");
-    let clients = schema::get_clients(client, Some(&config.name)).await?;
-    let mut server_conf = gen_server_interface(config, server);
-    server_conf += &gen_server_to_server_peers(config, &servers);
-    server_conf += &gen_server_to_client_peers(&clients);
+    let connections = schema::get_clients(client, None::<&str>).await?;
+    let subnets = schema::get_routed_subnets(client).await?;
+    let links = schema::get_server_links(client).await?;
+    let preshared_keys = preshared_keys::ensure(config, &connections).await?;
+    endpoints.resolve_neighbors(config, &servers, &links).await;
+    let private_key = rotation::active_private_key(config, server).await?;
+    let mut server_conf = gen_server_interface(server, &private_key);
+    server_conf +=
+        &gen_server_to_server_peers(config, &servers, &links, &connections, &subnets, endpoints);
+    server_conf += &gen_server_to_client_peers(config, &connections, &subnets, &preshared_keys);
     Ok(server_conf)
 }
 
 /// Generate the `[Interface]` part of the server configuration.
-fn gen_server_interface(config: &ServerConfig, server: &Server) -> String {
+fn gen_server_interface(server: &Server, private_key: &str) -> String {
     let mut conf = String::new();
     conf += "[Interface]\n";
     conf += &format!("ListenPort = {}\n", server.public_port);
-    conf += &format!("PrivateKey = {}\n", config.private_key);
+    conf += &format!("PrivateKey = {}\n", private_key);
     conf
 }
 
+/// The name of the server currently routing all the addresses of a client: the one chosen by the
+/// servers or, if the client has never been seen online, its preferred one. `connections` must be
+/// sorted by priority.
+fn active_server<'a>(name: &str, connections: &'a [ClientConnection]) -> Option<&'a str> {
+    let client_connections: Vec<_> = connections
+        .iter()
+        .filter(|c| c.client.name == name)
+        .collect();
+    let preferred = *client_connections.first()?;
+    match &preferred.client.active_server {
+        Some(server) if client_connections.iter().any(|c| c.server == *server) => {
+            Some(server.as_str())
+        }
+        _ => Some(preferred.server.as_str()),
+    }
+}
+
+/// The network with only the specified address.
+fn host_network(address: IpAddr) -> (IpAddr, u8) {
+    let len = if address.is_ipv4() { 32 } else { 128 };
+    (address, len)
+}
+
+/// Format a list of networks for the `AllowedIPs` field.
+fn format_networks(networks: &[(IpAddr, u8)]) -> String {
+    let networks: Vec<_> = networks
+        .iter()
+        .map(|(addr, len)| format!("{}/{}", addr, len))
+        .collect();
+    networks.join(", ")
+}
+
 /// Generate the `[Peer]` part of the server configuration relative to the connection with the other
-/// servers in the network.
-fn gen_server_to_server_peers(config: &ServerConfig, servers: &[Server]) -> String {
-    let mut conf = String::new();
+/// servers in the network. Only the servers directly linked with this one are peers, the networks of
+/// the other servers are routed through the next hop in the shortest path to them.
+fn gen_server_to_server_peers(
+    config: &ServerConfig,
+    servers: &[Server],
+    links: &[(String, String)],
+    connections: &[ClientConnection],
+    subnets: &[RoutedSubnet],
+    endpoints: &EndpointResolver,
+) -> String {
+    let topology = Topology::new(servers, links);
+    let next_hops = topology.next_hops(&config.name);
+    let keepalive = servers
+        .iter()
+        .find(|s| s.name == config.name)
+        .and_then(|s| s.keepalive)
+        .or(config.keepalive);
+    // the networks routed through each neighbor
+    let mut routes: HashMap<&str, Vec<(IpAddr, u8)>> = HashMap::new();
     for server in servers {
         if server.name == config.name {
             continue;
         }
+        let hop = match next_hops.get(&server.name) {
+            Some(hop) => hop,
+            None => {
+                warn!("Server {} is not reachable from this server", server.name);
+                continue;
+            }
+        };
+        let networks = routes.entry(hop.as_str()).or_default();
+        networks.extend(server.subnets());
+        // all the addresses of the clients routed by that server, including the ones of the other
+        // subnets and of this server: the client may be connected to this server, but its tunnel
+        // with this server may be down.
+        for conn in connections.iter().filter(|c| c.server != server.name) {
+            if active_server(&conn.client.name, connections) == Some(server.name.as_str()) {
+                networks.extend(conn.addresses().into_iter().map(host_network));
+            }
+        }
+        // the subnets behind the clients of that server
+        for subnet in subnets.iter().filter(|s| s.server == server.name) {
+            networks.push((subnet.subnet_addr, subnet.subnet_len));
+        }
+    }
+    let mut conf = String::new();
+    for name in topology.neighbors(&config.name) {
+        let server = servers.iter().find(|s| s.name == name).unwrap();
+        let allowed_ips = aggregate(routes.get(name).map(|r| r.as_slice()).unwrap_or_default());
         conf += "\n";
         conf += "[Peer]\n";
         conf += &format!("PublicKey = {}\n", server.public_key);
-        conf += &format!(
-            "AllowedIPs = {}/{}\n",
-            server.subnet_addr, server.subnet_len
-        );
-        conf += &format!(
-            "Endpoint = {}:{}\n",
-            server.public_address, server.public_port
-        );
-        if let Some(keepalive) = config.keepalive {
+        conf += &format!("AllowedIPs = {}\n", format_networks(&allowed_ips));
+        if let Some(preshared_key) = config.link_preshared_keys.get(name) {
+            conf += &format!("PresharedKey = {}\n", preshared_key);
+        }
+        match endpoints.endpoint(server) {
+            Some(endpoint) => conf += &format!("Endpoint = {}\n", endpoint),
+            // the server can still be reached when the other end connects first
+            None => warn!("The endpoint of {} is not known", server.name),
+        }
+        if let Some(keepalive) = keepalive {
             conf += &format!("PersistentKeepalive = {}\n", keepalive);
         }
+        // the new key of a server during a key rotation can only complete an handshake, the
+        // networks move to it once the rotation is completed.
+        if let Some(pending_public_key) = &server.pending_public_key {
+            conf += "\n";
+            conf += "[Peer]\n";
+            conf += &format!("PublicKey = {}\n", pending_public_key);
+            if let Some(preshared_key) = config.link_preshared_keys.get(name) {
+                conf += &format!("PresharedKey = {}\n", preshared_key);
+            }
+        }
     }
     conf
 }
 
 /// Generate the `[Peer]` part of the server configuration relative to the connection with the
-/// authorized clients.
-fn gen_server_to_client_peers(clients: &[ClientConnection]) -> String {
+/// authorized clients. The clients connected to more servers use all their addresses through the
+/// server currently routing them, on the other servers they can only complete an handshake. The
+/// subnets behind the clients are routed through them.
+fn gen_server_to_client_peers(
+    config: &ServerConfig,
+    connections: &[ClientConnection],
+    subnets: &[RoutedSubnet],
+    preshared_keys: &BTreeMap<String, String>,
+) -> String {
     let mut conf = String::new();
-    for client in clients {
+    for client in connections.iter().filter(|c| c.server == config.name) {
+        let preshared_key = if client.use_preshared_key {
+            preshared_keys.get(&client.client.name)
+        } else {
+            None
+        };
+        let mut allowed_ips = Vec::new();
+        if active_server(&client.client.name, connections) == Some(config.name.as_str()) {
+            allowed_ips.extend(
+                connections
+                    .iter()
+                    .filter(|c| c.client.name == client.client.name)
+                    .flat_map(|c| c.addresses())
+                    .map(host_network),
+            );
+        }
+        for subnet in subnets {
+            if subnet.server == config.name && subnet.client == client.client.name {
+                allowed_ips.push((subnet.subnet_addr, subnet.subnet_len));
+            }
+        }
         conf += "\n";
         conf += "[Peer]\n";
         conf += &format!("PublicKey = {}\n", client.client.public_key);
-        let len = if client.address.is_ipv4() { 32 } else { 128 };
-        conf += &format!("AllowedIPs = {}/{}\n", client.address, len);
+        if !allowed_ips.is_empty() {
+            conf += &format!("AllowedIPs = {}\n", format_networks(&allowed_ips));
+        }
+        if let Some(preshared_key) = preshared_key {
+            conf += &format!("PresharedKey = {}\n", preshared_key);
+        }
+        // a client behind NAT is kept reachable from the server too
+        if let Some(keepalive) = client.client.keepalive {
+            conf += &format!("PersistentKeepalive = {}\n", keepalive);
+        }
+        // the new key of a client during a key rotation can only complete an handshake, like the new
+        // key of a server: the addresses move to it once the handshake is seen and the key is
+        // promoted, the old key keeps working until then.
+        if let Some(pending_public_key) = &client.client.pending_public_key {
+            conf += "\n";
+            conf += "[Peer]\n";
+            conf += &format!("PublicKey = {}\n", pending_public_key);
+            if let Some(preshared_key) = preshared_key {
+                conf += &format!("PresharedKey = {}\n", preshared_key);
+            }
+            if let Some(keepalive) = client.client.keepalive {
+                conf += &format!("PersistentKeepalive = {}\n", keepalive);
+            }
+        }
     }
     conf
 }
 
-/// Generate the configuration file of a client. If the private key has not been passed, a
-/// placeholder is used instead.
+/// The DNS servers of a client, whether they are recursive and the search domains. The servers are
+/// the ones of `client_dns_servers` in the configuration file, otherwise the ones with the `dns`
+/// flag in the database, starting from the preferred one and the others the client is connected
+/// to. They are recursive resolvers unless `client_dns_zone_only` is set.
+async fn gen_client_dns(
+    config: &ServerConfig,
+    client: &Client,
+    connections: &[ServerConnection],
+    primary: usize,
+) -> Result<(Vec<String>, bool, Vec<String>), Error> {
+    let recursive = !config.client_dns_zone_only;
+    let servers = match &config.client_dns_servers {
+        Some(servers) => servers.clone(),
+        None => {
+            let mut servers = schema::get_servers(client).await?;
+            servers.retain(|s| s.dns);
+            let primary = &connections[primary].server.name;
+            servers.sort_by_key(|s| {
+                (
+                    s.name != *primary,
+                    !connections.iter().any(|c| c.server.name == s.name),
+                )
+            });
+            servers
+                .iter()
+                .flat_map(|s| s.addresses())
+                .map(|a| a.to_string())
+                .collect()
+        }
+    };
+    // without servers the search domains are useless
+    if servers.is_empty() {
+        return Ok((vec![], recursive, vec![]));
+    }
+    let search = match &config.client_dns_search {
+        Some(search) => search.clone(),
+        None => vec![config.base_domain.clone()],
+    };
+    Ok((servers, recursive, search))
+}
+
+/// Generate the configuration file of a client in the specified format. If the private key or the
+/// preshared keys (by name of the server) have not been passed, a placeholder is used instead.
 pub async fn gen_client_config(
     config: &ServerConfig,
     client: &Client,
-    name: String,
+    name: DnsLabel,
     private_key: Option<String>,
+    preshared_keys: &BTreeMap<String, String>,
+    primary: Option<String>,
+    format: ClientFormat,
 ) -> Result<String, Error> {
-    let connections = schema::get_client_connections(&client, &name).await?;
+    let model =
+        gen_client_model(config, client, name, private_key, preshared_keys, primary).await?;
+    model.render(format)
+}
+
+/// The networks a client routes through one of its servers. Everything goes through the preferred
+/// server: the entire network and the subnets behind the other clients, or all the traffic if it's
+/// the exit node. Nothing goes through the other servers, they accept the traffic of the client
+/// only once they become its active server (see `active_server`), which happens when the client is
+/// online only on them.
+fn gen_client_allowed_ips(
+    config: &ServerConfig,
+    exit_node: bool,
+    primary: bool,
+    subnets: &[(IpAddr, u8)],
+) -> Result<Vec<(IpAddr, u8)>, Error> {
+    if !primary {
+        return Ok(vec![]);
+    }
+    if exit_node {
+        return Ok(vec![
+            (IpAddr::from_str("0.0.0.0").unwrap(), 0),
+            (IpAddr::from_str("::").unwrap(), 0),
+        ]);
+    }
+    let mut allowed_ips = Vec::new();
+    for (network, len) in config.networks() {
+        match IpAddr::from_str(&network) {
+            Ok(network) => allowed_ips.push((network, len)),
+            Err(e) => bail!("Invalid network {}: {:?}", network, e),
+        }
+    }
+    allowed_ips.extend(subnets.iter().cloned());
+    Ok(allowed_ips)
+}
+
+/// Build the configuration of a client, independent from the format.
+///
+/// The client has an address for each server it can connect to, and the entire network is routed
+/// through the preferred server. The other servers are peers without routes, one of them can be
+/// made the preferred one with `primary` in case of failure.
+async fn gen_client_model(
+    config: &ServerConfig,
+    client: &Client,
+    name: DnsLabel,
+    private_key: Option<String>,
+    preshared_keys: &BTreeMap<String, String>,
+    primary: Option<String>,
+) -> Result<ClientConfig, Error> {
+    let name = name.as_str();
+    let user = match schema::get_client(client, name).await? {
+        Some(user) => user,
+        None => bail!("The user {} does not exist", name),
+    };
+    let connections = schema::get_client_connections(client, name).await?;
     if connections.is_empty() {
-        bail!("The user doesn't have a connection to any server");
+        bail!("The user doesn't have a valid and enabled connection to any server");
     }
-    let private_key = private_key.unwrap_or_else(|| "<insert your private key>".to_string());
-    let addresses: Vec<_> = connections
-        .iter()
-        .map(|c| format!("{}/{}", c.address.to_string(), config.netmask_len))
+    let primary = match primary {
+        Some(primary) => match connections.iter().position(|c| c.server.name == primary) {
+            Some(index) => index,
+            None => bail!("The user doesn't have a connection to {}", primary),
+        },
+        None => 0,
+    };
+    // the subnets behind the other clients are routed through the preferred server
+    let subnets: Vec<_> = schema::get_routed_subnets(client)
+        .await?
+        .into_iter()
+        .filter(|s| s.client != name)
+        .map(|s| (s.subnet_addr, s.subnet_len))
         .collect();
-    let addresses = addresses.join(",");
-
-    let mut conf = String::new();
-    conf += "[Interface]\n";
-    conf += &format!("PrivateKey = {}\n", private_key);
-    conf += &format!("Address = {}\n", addresses);
+    let addresses = connections
+        .iter()
+        .flat_map(|c| c.addresses())
+        .map(|addr| match config.netmask_len_of(&addr) {
+            Some(len) => Ok((addr, len)),
+            None => Err(format_err!(
+                "No network configured for the address {}",
+                addr
+            )),
+        })
+        .collect::<Result<_, Error>>()?;
+    let (dns_servers, dns_recursive, dns_search) = if user.push_dns {
+        gen_client_dns(config, client, &connections, primary).await?
+    } else {
+        (vec![], false, vec![])
+    };
+    let keepalive = user.keepalive.or(config.keepalive);
 
-    // this is a list, even tho there could be at most one entry
-    for connection in connections {
+    let mut peers = Vec::new();
+    for (index, connection) in connections.into_iter().enumerate() {
         let server = connection.server;
-        conf += "\n";
-        conf += "[Peer]\n";
-        conf += &format!("PublicKey = {}\n", server.public_key);
-        conf += &format!("AllowedIPs = {}/{}\n", config.network, config.netmask_len);
-        conf += &format!(
-            "Endpoint = {}:{}\n",
-            server.public_address.to_string(),
-            server.public_port
+        // the hostname is emitted verbatim, it's resolved by the client
+        let endpoint_host = match server.public_host() {
+            Some(host) => host,
+            None if index == primary => bail!("The server {} has no public address", server.name),
+            None => {
+                warn!("Skipping {}, it has no public address", server.name);
+                continue;
+            }
+        };
+        let allowed_ips =
+            gen_client_allowed_ips(config, connection.exit_node, index == primary, &subnets)?;
+        // the preshared keys are kept only by the servers, they are never in the database
+        let preshared_key = if connection.use_preshared_key {
+            let key = preshared_keys.get(&server.name).cloned();
+            Some(key.unwrap_or_else(|| "<insert the preshared key>".to_string()))
+        } else {
+            None
+        };
+        peers.push(ClientPeer {
+            endpoint_host,
+            endpoint_port: server.public_port,
+            public_key: server.public_key,
+            preshared_key,
+            allowed_ips,
+            keepalive,
+            server: server.name,
+        });
+    }
+
+    Ok(ClientConfig {
+        name: user.name,
+        domain: config.base_domain.clone(),
+        private_key: private_key.unwrap_or_else(|| "<insert your private key>".to_string()),
+        addresses,
+        mtu: user.mtu,
+        dns_servers,
+        dns_recursive,
+        dns_search,
+        peers,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+    use crate::schema::Client;
+
+    fn test_server(name: &str, index: u8) -> Server {
+        Server {
+            name: name.to_string(),
+            subnet_addr: IpAddr::from([10, 0, index, 0]),
+            subnet_len: 24,
+            address: IpAddr::from([10, 0, index, 1]),
+            public_address: Some(IpAddr::from([192, 0, 2, index])),
+            public_port: 51820,
+            public_key: format!("{}-key", name),
+            hub: false,
+            public_hostname: None,
+            keepalive: None,
+            mtu: None,
+            secondary_subnet_addr: None,
+            secondary_subnet_len: None,
+            secondary_address: None,
+            pending_public_key: None,
+            dns: false,
+        }
+    }
+
+    fn test_connection(server: &Server, name: &str, host: u8, priority: i32) -> ClientConnection {
+        let mut address = match server.subnet_addr {
+            IpAddr::V4(addr) => addr.octets(),
+            IpAddr::V6(_) => unreachable!(),
+        };
+        address[3] = host;
+        ClientConnection {
+            server: server.name.clone(),
+            client: Client {
+                name: name.to_string(),
+                public_key: format!("{}-key", name),
+                active_server: None,
+                keepalive: None,
+                mtu: None,
+                pending_public_key: None,
+                push_dns: true,
+            },
+            address: IpAddr::from(address),
+            priority,
+            exit_node: false,
+            secondary_address: None,
+            use_preshared_key: false,
+            enabled: true,
+            disabled_reason: None,
+            disabled_at: None,
+        }
+    }
+
+    /// The `AllowedIPs` of the peer with the specified public key.
+    fn allowed_ips<'a>(conf: &'a str, public_key: &str) -> Option<&'a str> {
+        let peer = conf
+            .split("[Peer]\n")
+            .find(|p| p.starts_with(&format!("PublicKey = {}\n", public_key)))?;
+        Some(
+            peer.lines()
+                .find(|l| l.starts_with("AllowedIPs = "))
+                .map(|l| &l["AllowedIPs = ".len()..])
+                .unwrap_or_default(),
+        )
+    }
+
+    /// A client connected to `alpha` (preferred) and `beta`, in a network with also `gamma`.
+    fn failover_network(active: &str) -> (Vec<Server>, Vec<ClientConnection>) {
+        let servers = vec![
+            test_server("alpha", 1),
+            test_server("beta", 2),
+            test_server("gamma", 3),
+        ];
+        let mut connections = vec![
+            test_connection(&servers[0], "client", 2, 0),
+            test_connection(&servers[1], "client", 2, 1),
+            test_connection(&servers[2], "other", 2, 0),
+        ];
+        for conn in &mut connections {
+            if conn.client.name == "client" {
+                conn.client.active_server = Some(active.to_string());
+            }
+        }
+        (servers, connections)
+    }
+
+    fn server_peers(name: &str, servers: &[Server], connections: &[ClientConnection]) -> String {
+        gen_server_to_server_peers(
+            &test_config(name, ""),
+            servers,
+            &[],
+            connections,
+            &[],
+            &EndpointResolver::default(),
+        )
+    }
+
+    #[test]
+    fn test_client_routed_through_active_server() {
+        let (servers, connections) = failover_network("alpha");
+        // the other servers route all the addresses of the client through alpha
+        for name in &["beta", "gamma"] {
+            let conf = server_peers(name, &servers, &connections);
+            assert_eq!(
+                allowed_ips(&conf, "alpha-key"),
+                Some("10.0.1.0/24, 10.0.2.2/32")
+            );
+        }
+        let conf = server_peers("gamma", &servers, &connections);
+        assert_eq!(allowed_ips(&conf, "beta-key"), Some("10.0.2.0/24"));
+        // alpha uses the addresses on its peer, beta only accepts handshakes
+        let conf = gen_server_to_client_peers(
+            &test_config("alpha", ""),
+            &connections,
+            &[],
+            &BTreeMap::new(),
         );
-        if let Some(keepalive) = config.keepalive {
-            conf += &format!("PersistentKeepalive = {}\n", keepalive);
+        assert_eq!(
+            allowed_ips(&conf, "client-key"),
+            Some("10.0.1.2/32, 10.0.2.2/32")
+        );
+        let conf = gen_server_to_client_peers(
+            &test_config("beta", ""),
+            &connections,
+            &[],
+            &BTreeMap::new(),
+        );
+        assert_eq!(allowed_ips(&conf, "client-key"), Some(""));
+    }
+
+    #[test]
+    fn test_client_failover() {
+        let (servers, connections) = failover_network("beta");
+        let conf = server_peers("alpha", &servers, &connections);
+        assert_eq!(
+            allowed_ips(&conf, "beta-key"),
+            Some("10.0.2.0/24, 10.0.1.2/32")
+        );
+        let conf = server_peers("gamma", &servers, &connections);
+        assert_eq!(allowed_ips(&conf, "alpha-key"), Some("10.0.1.0/24"));
+        assert_eq!(
+            allowed_ips(&conf, "beta-key"),
+            Some("10.0.2.0/24, 10.0.1.2/32")
+        );
+        let conf = gen_server_to_client_peers(
+            &test_config("alpha", ""),
+            &connections,
+            &[],
+            &BTreeMap::new(),
+        );
+        assert_eq!(allowed_ips(&conf, "client-key"), Some(""));
+        let conf = gen_server_to_client_peers(
+            &test_config("beta", ""),
+            &connections,
+            &[],
+            &BTreeMap::new(),
+        );
+        assert_eq!(
+            allowed_ips(&conf, "client-key"),
+            Some("10.0.1.2/32, 10.0.2.2/32")
+        );
+    }
+
+    #[test]
+    fn test_client_and_servers_agree() {
+        for active in &["alpha", "beta"] {
+            let (_, connections) = failover_network(active);
+            for conn in connections.iter().filter(|c| c.client.name == "client") {
+                // the client uses the active server as its primary one
+                let primary = conn.server == *active;
+                let routes =
+                    gen_client_allowed_ips(&test_config("client", ""), false, primary, &[])
+                        .unwrap();
+                let conf = gen_server_to_client_peers(
+                    &test_config(&conn.server, ""),
+                    &connections,
+                    &[],
+                    &BTreeMap::new(),
+                );
+                // a server gets traffic from the client only if it accepts its addresses
+                let accepted = allowed_ips(&conf, "client-key").unwrap();
+                if routes.is_empty() {
+                    assert_eq!(accepted, "", "{} gets no traffic", conn.server);
+                } else {
+                    assert_eq!(routes, vec![(IpAddr::from([10, 0, 0, 0]), 16)]);
+                    assert_eq!(accepted, "10.0.1.2/32, 10.0.2.2/32");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_routed_subnets() {
+        let (servers, connections) = failover_network("alpha");
+        let subnets = vec![RoutedSubnet {
+            subnet_addr: IpAddr::from([192, 168, 1, 0]),
+            subnet_len: 24,
+            server: "gamma".to_string(),
+            client: "other".to_string(),
+        }];
+        let conf = gen_server_to_server_peers(
+            &test_config("alpha", ""),
+            &servers,
+            &[],
+            &connections,
+            &subnets,
+            &EndpointResolver::default(),
+        );
+        assert_eq!(
+            allowed_ips(&conf, "gamma-key"),
+            Some("10.0.3.0/24, 192.168.1.0/24")
+        );
+        let conf = gen_server_to_client_peers(
+            &test_config("gamma", ""),
+            &connections,
+            &subnets,
+            &BTreeMap::new(),
+        );
+        assert_eq!(
+            allowed_ips(&conf, "other-key"),
+            Some("10.0.3.2/32, 192.168.1.0/24")
+        );
+    }
+
+    #[test]
+    fn test_preshared_keys() {
+        let (servers, mut connections) = failover_network("alpha");
+        let mut config = test_config("alpha", "");
+        config
+            .link_preshared_keys
+            .insert("beta".to_string(), "link-psk".to_string());
+        let conf = gen_server_to_server_peers(
+            &config,
+            &servers,
+            &[],
+            &connections,
+            &[],
+            &EndpointResolver::default(),
+        );
+        let beta = conf
+            .split("[Peer]\n")
+            .find(|p| p.contains("beta-key"))
+            .unwrap();
+        assert!(beta.contains("PresharedKey = link-psk\n"));
+        let gamma = conf
+            .split("[Peer]\n")
+            .find(|p| p.contains("gamma-key"))
+            .unwrap();
+        assert!(!gamma.contains("PresharedKey"));
+
+        let mut keys = BTreeMap::new();
+        keys.insert("client".to_string(), "client-psk".to_string());
+        // a key is used only by the connections that enable it
+        let conf = gen_server_to_client_peers(&config, &connections, &[], &keys);
+        assert!(!conf.contains("PresharedKey"));
+        for conn in &mut connections {
+            conn.use_preshared_key = true;
+        }
+        let conf = gen_server_to_client_peers(&config, &connections, &[], &keys);
+        assert!(conf.contains("PresharedKey = client-psk\n"));
+    }
+
+    #[test]
+    fn test_pending_client_key() {
+        let (_, mut connections) = failover_network("alpha");
+        for conn in &mut connections {
+            conn.client.pending_public_key = Some(format!("{}-new-key", conn.client.name));
+        }
+        let conf = gen_server_to_client_peers(
+            &test_config("alpha", ""),
+            &connections,
+            &[],
+            &BTreeMap::new(),
+        );
+        // the addresses stay with the old key until the new one is promoted
+        assert_eq!(
+            allowed_ips(&conf, "client-key"),
+            Some("10.0.1.2/32, 10.0.2.2/32")
+        );
+        assert_eq!(allowed_ips(&conf, "client-new-key"), Some(""));
+        assert!(conf.contains("PublicKey = client-new-key\n"));
+        let conf = gen_server_to_client_peers(
+            &test_config("beta", ""),
+            &connections,
+            &[],
+            &BTreeMap::new(),
+        );
+        assert_eq!(allowed_ips(&conf, "client-new-key"), Some(""));
+    }
+
+    #[test]
+    fn test_client_keepalive() {
+        let (_, mut connections) = failover_network("alpha");
+        let conf = gen_server_to_client_peers(
+            &test_config("alpha", ""),
+            &connections,
+            &[],
+            &BTreeMap::new(),
+        );
+        assert!(!conf.contains("PersistentKeepalive"));
+        for conn in &mut connections {
+            conn.client.keepalive = Some(25);
+        }
+        // the keepalive of the client applies also to its peer on every server
+        for name in &["alpha", "beta"] {
+            let conf = gen_server_to_client_peers(
+                &test_config(name, ""),
+                &connections,
+                &[],
+                &BTreeMap::new(),
+            );
+            assert!(conf.contains("PublicKey = client-key\n"));
+            assert!(conf.contains("PersistentKeepalive = 25\n"));
         }
     }
 
-    Ok(conf)
+    #[test]
+    fn test_active_server() {
+        let (_, mut connections) = failover_network("beta");
+        assert_eq!(active_server("client", &connections), Some("beta"));
+        // a server the client is not connected to anymore is ignored
+        connections.retain(|c| c.server != "beta");
+        assert_eq!(active_server("client", &connections), Some("alpha"));
+        assert_eq!(active_server("unknown", &connections), None);
+    }
+
+    #[test]
+    fn test_gen_server_interface() {
+        let server = test_server("alpha", 1);
+        assert_eq!(
+            gen_server_interface(&server, "private"),
+            "[Interface]\nListenPort = 51820\nPrivateKey = private\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_peers_status() {
+        let dump = "cHJpdmF0ZQ==\tcHVibGlj\t51820\toff\n\
+                    a2V5MQ==\t(none)\t1.2.3.4:51820\t10.0.1.0/24\t1570000000\t1024\t2048\t25\n\
+                    a2V5Mg==\tcHNr\t(none)\t10.0.2.2/32\t0\t0\t0\toff\n";
+        let peers = parse_peers_status(dump).unwrap();
+        assert_eq!(peers.len(), 2);
+        assert_eq!(peers[0].public_key, "a2V5MQ==");
+        assert_eq!(peers[0].endpoint.as_deref(), Some("1.2.3.4:51820"));
+        assert_eq!(peers[0].latest_handshake, Some(1570000000));
+        assert_eq!(peers[0].transfer_rx, 1024);
+        assert_eq!(peers[0].transfer_tx, 2048);
+        assert_eq!(peers[1].public_key, "a2V5Mg==");
+        assert_eq!(peers[1].endpoint, None);
+        assert_eq!(peers[1].latest_handshake, None);
+    }
+
+    #[test]
+    fn test_parse_peers_status_no_peers() {
+        let dump = "cHJpdmF0ZQ==\tcHVibGlj\t51820\toff\n";
+        assert!(parse_peers_status(dump).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_parse_peers_status_malformed() {
+        let dump = "cHJpdmF0ZQ==\tcHVibGlj\t51820\toff\na2V5MQ==\t(none)\n";
+        assert!(parse_peers_status(dump).is_err());
+        let dump = "cHJpdmF0ZQ==\tcHVibGlj\t51820\toff\n\
+                    a2V5MQ==\t(none)\t(none)\t10.0.2.2/32\tnever\t0\t0\toff\n";
+        assert!(parse_peers_status(dump).is_err());
+    }
 }